@@ -0,0 +1,583 @@
+use super::lang;
+use super::lang::collect_atoms;
+use super::lang::find_var;
+use super::lang::required_var_refs;
+use super::lang::var_refs;
+use super::lang::visit;
+use super::lang::Expr;
+use super::lang::Source;
+use super::lang::Statement;
+use super::lang::StatementKind;
+use super::Command;
+use super::Line;
+use super::Mold;
+use super::NameSet;
+use super::Var;
+use colored::*;
+use failure::Error;
+use std::fs;
+use std::path::Path;
+
+// conditions with more distinct atoms than this are too expensive to solve
+const MAX_ATOMS: usize = 16;
+
+/// A single problem found in a Mold
+pub struct Problem {
+    /// Whether this should fail the check, as opposed to being a warning
+    pub error: bool,
+
+    /// A description of the problem, including the code it refers to when it's known
+    pub message: String,
+}
+
+impl Problem {
+    fn error(message: String) -> Self {
+        Problem {
+            error: true,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Problem {
+            error: false,
+            message,
+        }
+    }
+}
+
+/// A parsed moldfile, kept around for checks that need to see every branch of a condition
+struct File {
+    path: std::path::PathBuf,
+    code: String,
+    stmts: Vec<Statement>,
+}
+
+impl File {
+    fn source(&self) -> Source<'_> {
+        Source {
+            code: &self.code,
+            path: &self.path,
+        }
+    }
+}
+
+/// Check a loaded Mold for problems without running anything
+///
+/// Recipes and variables are checked as they were loaded with the active environments, but
+/// conditions are checked across every branch of every loaded moldfile. The Mold should be loaded
+/// in dry mode without `compute_conditions`, so conditions on computed variables are decided by
+/// their unexpanded `$(...)` text instead of executing them.
+pub fn check(mold: &Mold) -> Result<Vec<Problem>, Error> {
+    let mut files: Vec<File> = vec![];
+    for (path, _) in &mold.files {
+        // the same moldfile can be imported more than once with different prefixes
+        if files.iter().any(|file| &file.path == path) {
+            continue;
+        }
+
+        let code = fs::read_to_string(path).map_err(|err| {
+            failure::format_err!(
+                "Couldn't read {}: {}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        let stmts = lang::parse(&Source { code: &code, path })?;
+        files.push(File {
+            path: path.clone(),
+            code,
+            stmts,
+        });
+    }
+
+    let mut problems = vec![];
+    check_requires(mold, &mut problems);
+    check_cycles(mold, &mut problems);
+    check_undefined(mold, &mut problems);
+    check_unused(mold, &files, &mut problems);
+
+    for file in &files {
+        check_conditions(&file.source(), &file.stmts, &mut problems);
+    }
+
+    Ok(problems)
+}
+
+/// Find requires, aliases and default targets that point at recipes that don't exist or can't accept their
+/// arguments
+fn check_requires(mold: &Mold, problems: &mut Vec<Problem>) {
+    for (alias, name) in &mold.aliases {
+        if mold.recipes.contains_key(alias) {
+            problems.push(Problem::error(format!(
+                "Alias {} has the same name as a recipe",
+                alias.red()
+            )));
+        } else if !mold.recipes.contains_key(name) {
+            problems.push(Problem::error(format!(
+                "Alias {} refers to {}, which doesn't exist",
+                alias.red(),
+                name.red()
+            )));
+        }
+    }
+
+    for target in &mold.default_targets {
+        if !mold.has_recipe(&target.name) {
+            problems.push(Problem::error(format!(
+                "Default recipe {} doesn't exist",
+                target.name.red()
+            )));
+        } else if let Err(err) = mold.bind(target) {
+            problems.push(Problem::error(format!(
+                "Default recipe {}: {}",
+                target.to_string().red(),
+                err
+            )));
+        }
+    }
+
+    for (name, recipe) in &mold.recipes {
+        for target in &recipe.requires {
+            if !mold.has_recipe(&target.name) {
+                problems.push(Problem::error(format!(
+                    "Recipe {} requires {}, which doesn't exist",
+                    name.red(),
+                    target.name.red()
+                )));
+            } else if let Err(err) = mold.bind(target) {
+                problems.push(Problem::error(format!(
+                    "Recipe {} requires {}: {}",
+                    name.red(),
+                    target.to_string().red(),
+                    err
+                )));
+            }
+        }
+    }
+}
+
+/// Find recipes that end up requiring themselves
+fn check_cycles(mold: &Mold, problems: &mut Vec<Problem>) {
+    let mut done = NameSet::new();
+
+    for name in mold.recipes.keys() {
+        let mut path = vec![];
+        find_cycles(mold, name, &mut path, &mut done, problems);
+    }
+}
+
+fn find_cycles<'a>(
+    mold: &'a Mold,
+    name: &'a str,
+    path: &mut Vec<&'a str>,
+    done: &mut NameSet,
+    problems: &mut Vec<Problem>,
+) {
+    if let Some(idx) = path.iter().position(|x| *x == name) {
+        let mut cycle = path[idx..].to_vec();
+        cycle.push(name);
+        problems.push(Problem::error(format!(
+            "Recipes require each other in a cycle: {}",
+            cycle.join(" -> ").red()
+        )));
+        return;
+    }
+
+    if done.contains(name) {
+        return;
+    }
+
+    if let Some(recipe) = mold.recipes.get(name) {
+        path.push(name);
+        for target in &recipe.requires {
+            find_cycles(mold, mold.resolve(&target.name), path, done, problems);
+        }
+        path.pop();
+    }
+
+    done.insert(name.into());
+}
+
+/// Find variables that are expanded by a recipe without being defined anywhere
+fn check_undefined(mold: &Mold, problems: &mut Vec<Problem>) {
+    let mut globals = NameSet::new();
+    globals.extend(std::env::vars().map(|(name, _)| name));
+    for (name, var) in &mold.vars {
+        if *var == Var::Unset {
+            globals.shift_remove(name);
+        } else {
+            globals.insert(name.clone());
+        }
+    }
+
+    for (name, value) in &mold.vars {
+        if let Var::Value(text) | Var::Command(text) = value {
+            for var_name in required_var_refs(text) {
+                if !globals.contains(&var_name) {
+                    problems.push(Problem::error(format!(
+                        "Variable {} from {} uses undefined variable {}",
+                        name.red(),
+                        mold.var_origin(name).red(),
+                        format!("${}", var_name).red()
+                    )));
+                }
+            }
+        }
+    }
+
+    for (name, recipe) in &mold.recipes {
+        let mut defined = globals.clone();
+        defined.insert("MOLD_SOURCE".into());
+        defined.extend(recipe.params.iter().map(|param| param.name.clone()));
+        let import_vars = mold.import_vars.get(name).into_iter().flatten();
+        for (var_name, var) in import_vars.chain(&recipe.vars) {
+            if *var == Var::Unset {
+                defined.shift_remove(var_name);
+            } else {
+                defined.insert(var_name.clone());
+            }
+        }
+
+        let mut texts: Vec<&str> = vec![];
+        texts.extend(recipe.dir.as_deref().or(mold.work_dir.as_deref()));
+        for line in recipe.lines() {
+            texts.extend(line.shell.as_deref());
+        }
+        for var in recipe.vars.values() {
+            if let Var::Value(text) | Var::Command(text) = var {
+                texts.push(text);
+            }
+        }
+
+        // command lines passed to a shell can use the shell's own variables, like loop variables
+        // or $1, so there's no telling which of their references are undefined
+        let unshelled = |line: &&Line| line.shell.is_none();
+        for command in &recipe.commands {
+            match command {
+                Command::Run(line, _) if line.shell.is_none() => texts.push(&line.text),
+                Command::Run(..) | Command::Script(..) => {}
+                Command::File(path, _) => texts.push(path),
+            }
+        }
+        let hooks = &recipe.hooks;
+        let hooks = hooks.before.iter().chain(&hooks.after).filter(unshelled);
+        texts.extend(hooks.map(|x| x.text.as_str()));
+
+        // finally hooks are the only commands that get to see how the recipe went
+        let finally_refs = recipe
+            .hooks
+            .finally
+            .iter()
+            .filter(unshelled)
+            .flat_map(|x| required_var_refs(&x.text));
+        let finally_refs = finally_refs.filter(|x| x != "MOLD_STATUS");
+
+        let refs = texts.into_iter().flat_map(required_var_refs);
+        for var_name in refs.chain(finally_refs) {
+            if !defined.contains(&var_name) {
+                problems.push(Problem::error(format!(
+                    "Recipe {} uses undefined variable {}",
+                    name.red(),
+                    format!("${}", var_name).red()
+                )));
+            }
+        }
+    }
+}
+
+/// Find variables that are defined but never mentioned anywhere
+///
+/// Variables are exported to every command, so a variable might still be used by a program that
+/// reads its environment. Scripts are searched for any mention of a variable's name for that
+/// reason, and unused variables are only reported as warnings.
+fn check_unused(mold: &Mold, files: &[File], problems: &mut Vec<Problem>) {
+    let mut used = NameSet::new();
+
+    for var in mold.vars.values() {
+        if let Var::Value(text) | Var::Command(text) = var {
+            used.extend(var_refs(text));
+        }
+    }
+
+    for runtime in mold.runtimes.values() {
+        used.extend(var_refs(&runtime.command));
+    }
+
+    used.extend(mold.work_dir.iter().flat_map(|x| var_refs(x)));
+
+    for (name, recipe) in &mold.recipes {
+        used.extend(recipe.dir.iter().flat_map(|x| var_refs(x)));
+        for line in recipe.lines() {
+            used.extend(var_refs(&line.text));
+            used.extend(line.shell.iter().flat_map(|x| var_refs(x)));
+        }
+        for var in recipe.vars.values() {
+            if let Var::Value(text) | Var::Command(text) = var {
+                used.extend(var_refs(text));
+            }
+        }
+
+        for command in &recipe.commands {
+            match command {
+                Command::Run(..) => {}
+                Command::Script(runtime, body) => {
+                    used.extend(var_refs(runtime));
+                    used.extend(words(body));
+                }
+                Command::File(path, runtime) => {
+                    used.extend(var_refs(path));
+                    used.extend(runtime.iter().flat_map(|x| var_refs(x)));
+
+                    // scripts can only be read if their path doesn't depend on a variable
+                    let dir = mold.sources.get(name);
+                    if let (Some(dir), false) = (dir, path.contains('$')) {
+                        if let Ok(code) = fs::read_to_string(dir.join(path)) {
+                            used.extend(words(&code));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for file in files {
+        visit(&file.stmts, &mut |stmt| {
+            if let StatementKind::If(expr, _) = &stmt.kind {
+                expr.vars(&mut used);
+            }
+        });
+    }
+
+    for (name, path) in &mold.var_sources {
+        if used.contains(name) {
+            continue;
+        }
+
+        let message = format!("Variable ${} is defined but never used", name);
+        let located = files
+            .iter()
+            .filter(|file| &file.path == path)
+            .find_map(|file| find_var(&file.stmts, name).map(|span| (file, span)));
+
+        problems.push(Problem::warning(match located {
+            Some((file, span)) => file.source().error(span, message).to_string(),
+            None => format!("{} in {}", message, path.display()),
+        }));
+    }
+
+    for (name, recipe) in &mold.recipes {
+        for var_name in recipe.vars.keys() {
+            if !used.contains(var_name) {
+                problems.push(Problem::warning(format!(
+                    "Variable {} in recipe {} is defined but never used",
+                    format!("${}", var_name).red(),
+                    name.red()
+                )));
+            }
+        }
+    }
+}
+
+/// Find conditions that can never be true, either on their own or because of the cases before
+/// them, which means the env atoms they test can never activate them
+fn check_conditions(source: &Source, stmts: &[Statement], problems: &mut Vec<Problem>) {
+    visit(stmts, &mut |stmt| {
+        let cases = match &stmt.kind {
+            StatementKind::IfBlock(cases) => cases,
+            _ => return,
+        };
+
+        let mut previous: Vec<&Expr> = vec![];
+        for case in cases {
+            let (expr, message) = match &case.kind {
+                StatementKind::If(expr, _) => (Some(expr), "This condition can never be true"),
+                _ => (None, "This else can never be reached"),
+            };
+
+            if let Some(false) = satisfiable(expr, &previous) {
+                problems.push(Problem::error(
+                    source.error(case.span, message.into()).to_string(),
+                ));
+            }
+
+            previous.extend(expr);
+        }
+    });
+}
+
+/// Decide whether `expr` can be true while every one of `previous` is false
+///
+/// Anything that isn't an env atom is treated as something that could go either way. Returns
+/// None when there are too many atoms to try every combination of them.
+fn satisfiable(expr: Option<&Expr>, previous: &[&Expr]) -> Option<bool> {
+    let mut atoms = vec![];
+    for x in expr.iter().chain(previous) {
+        collect_atoms(x, &mut atoms);
+    }
+
+    if atoms.len() > MAX_ATOMS {
+        return None;
+    }
+
+    let found = (0..1u32 << atoms.len()).any(|bits| {
+        let truth = |atom: &Expr| {
+            let idx = atoms.iter().position(|x| *x == atom).unwrap();
+            bits & (1 << idx) != 0
+        };
+
+        expr.is_none_or(|x| eval(x, &truth)) && previous.iter().all(|x| !eval(x, &truth))
+    });
+
+    Some(found)
+}
+
+fn eval<F>(expr: &Expr, truth: &F) -> bool
+where
+    F: Fn(&Expr) -> bool,
+{
+    match expr {
+        Expr::And(x, y) => eval(x, truth) && eval(y, truth),
+        Expr::Or(x, y) => eval(x, truth) || eval(y, truth),
+        Expr::Not(x) => !eval(x, truth),
+        Expr::Group(x) => eval(x, truth),
+        Expr::Wild => true,
+        _ => truth(expr),
+    }
+}
+
+/// Split some code into everything that could be a variable name
+fn words(code: &str) -> impl Iterator<Item = String> + '_ {
+    code.split(|x: char| !(x.is_ascii_alphanumeric() || x == '_'))
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+}
+
+/// Print the problems found in a Mold, returning an error if any of them should fail the check
+pub fn report(problems: &[Problem], path: &Path) -> Result<(), Error> {
+    let mut errors = 0;
+
+    for problem in problems {
+        let label = if problem.error {
+            errors += 1;
+            "error".red()
+        } else {
+            "warning".yellow()
+        };
+
+        if problem.message.starts_with(' ') {
+            println!("{}:\n{}\n", label, problem.message);
+        } else {
+            println!("{}: {}\n", label, problem.message);
+        }
+    }
+
+    if errors > 0 {
+        return Err(failure::format_err!(
+            "{} has {} error(s)",
+            path.display().to_string().red(),
+            errors
+        ));
+    }
+
+    println!(
+        "{:>12} {} with {} warning(s)",
+        "Checked".green(),
+        path.display(),
+        problems.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    /// Check a moldfile the way --check does, returning the messages of its errors and warnings
+    fn problems(name: &str, code: &str) -> (Vec<String>, Vec<String>) {
+        let dir = scratch(name, &[("moldfile", code)]);
+        let path = dir.join("moldfile");
+        let mold = Mold::init(&path, vec![], false, true, false, true, false).unwrap();
+        let (errors, warnings): (Vec<_>, Vec<_>) =
+            check(&mold).unwrap().into_iter().partition(|x| x.error);
+        let messages = |x: Vec<Problem>| x.into_iter().map(|x| x.message).collect();
+        (messages(errors), messages(warnings))
+    }
+
+    fn errors(name: &str, code: &str) -> Vec<String> {
+        problems(name, code).0
+    }
+
+    #[test]
+    fn finds_missing_and_mismatched_requires() {
+        let found = errors(
+            "check-requires",
+            "version \"0.7\"\ndefault nope\nalias a = missing\n\
+             recipe r(x) { $ \"echo $x\" }\nrecipe s {\n  require t\n  require r\n}\n",
+        );
+        assert_eq!(found.len(), 4, "{:?}", found);
+        assert!(found[0].contains("refers to"));
+        assert!(found[1].contains("doesn't exist"));
+        assert!(found[2].contains("which doesn't exist"));
+        assert!(found[3].contains("missing required argument"));
+    }
+
+    #[test]
+    fn finds_cycles() {
+        let found = errors(
+            "check-cycles",
+            "version \"0.7\"\nrecipe a { require b }\nrecipe b { require a }\n",
+        );
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].contains("in a cycle"));
+    }
+
+    #[test]
+    fn finds_undefined_variables() {
+        let found = errors(
+            "check-undefined",
+            "version \"0.7\"\nvar A = \"$MOLD_TEST_A\"\n\
+             recipe r(x) {\n  finally \"echo $MOLD_STATUS $x\"\n  $ \"echo $MOLD_TEST_B ${MOLD_TEST_C:-c}\"\n}\n\
+             recipe s {\n  shell \"sh -c\"\n  $ \"for f in *; do echo $f; done\"\n}\n",
+        );
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0].contains("MOLD_TEST_A"));
+        assert!(found[1].contains("MOLD_TEST_B"));
+    }
+
+    #[test]
+    fn warns_about_unused_variables() {
+        let (errors, warnings) = problems(
+            "check-unused",
+            "version \"0.7\"\nvar USED = \"a\"\nvar UNUSED = \"b\"\nvar SCRIPT = \"c\"\n\
+             var COND = \"d\"\nif $COND == \"d\" {\n  recipe r {\n    var LOCAL = \"e\"\n    $ \"echo $USED\"\n  }\n}\n\
+             recipe s { script \"python\" \"import os; print(os.environ['SCRIPT'])\" }\n",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].contains("$UNUSED"));
+        assert!(warnings[0].contains("moldfile:3:1"));
+        assert!(warnings[1].contains("LOCAL"));
+    }
+
+    #[test]
+    fn finds_conditions_that_cant_be_true() {
+        let found = errors(
+            "check-conditions",
+            "version \"0.7\"\nif linux {\n  var A = \"a\"\n} elif linux {\n  var A = \"b\"\n}\n\
+             if * {\n  var B = \"a\"\n} else {\n  var B = \"b\"\n}\n\
+             if linux + ~linux {\n  var C = \"a\"\n}\n\
+             if $D == \"x\" {\n  var D = \"a\"\n} elif $D == \"y\" {\n  var D = \"b\"\n} else {\n  var D = \"c\"\n}\n\
+             if linux | ~linux {\n  var E = \"a\"\n} elif mac {\n  var E = \"b\"\n}\n",
+        );
+        assert_eq!(found.len(), 4, "{:?}", found);
+        assert!(found[0].contains("moldfile:4:3"));
+        assert!(found[0].contains("can never be true"));
+        assert!(found[1].contains("else can never be reached"));
+        assert!(found[2].contains("moldfile:12:1"));
+        assert!(found[3].contains("moldfile:24:3"));
+    }
+}
@@ -0,0 +1,628 @@
+use super::lang::parse_tree;
+use super::lang::Rule;
+use super::lang::Source;
+use failure::Error;
+use pest::iterators::Pair;
+
+const INDENT: &str = "  ";
+
+/// A comment and where it appears in a moldfile's code
+struct Comment<'a> {
+    start: usize,
+    end: usize,
+    text: &'a str,
+}
+
+/// Either a comment or a statement (with its trailing comment) in a body
+enum Entry<'a> {
+    Comment(&'a str),
+    Statement(Pair<'a, Rule>, Option<&'a str>),
+}
+
+/// An Entry and whether it was preceded by a blank line
+struct Item<'a> {
+    blank: bool,
+    entry: Entry<'a>,
+}
+
+/// A statement and the comments directly above it, which get moved around together
+struct Chunk<'a> {
+    items: Vec<Item<'a>>,
+    rank: usize,
+}
+
+impl Chunk<'_> {
+    /// The statement at the end of this chunk, if it has one
+    fn rule(&self) -> Option<Rule> {
+        match self.items.last().map(|x| &x.entry) {
+            Some(Entry::Statement(pair, _)) => Some(pair.as_rule()),
+            _ => None,
+        }
+    }
+}
+
+/// Given a moldfile's Source, rewrite it in the canonical style
+///
+/// Comments are silent in the grammar, so they're located with a separate scan of the code and
+/// then attached to the statement that follows them (or precedes them, if they're on the same
+/// line). `version` and `import` statements are moved to the top of the file and `help`
+/// statements are moved to the top of their recipe, since their position doesn't matter.
+/// Everything else keeps its order, and `run` is always written as `$`.
+pub fn format(source: &Source) -> Result<String, Error> {
+    let pairs = parse_tree(source)?;
+    let mut formatter = Formatter {
+        code: source.code,
+        comments: scan_comments(source.code),
+        out: String::new(),
+    };
+
+    let stmts: Vec<_> = pairs.filter(|x| x.as_rule() != Rule::EOI).collect();
+
+    // the order of imports decides which recipes win, so imports can't be moved past a
+    // conditional import
+    let fixed = stmts.iter().any(|x| {
+        x.as_rule() == Rule::if_block
+            && x.clone()
+                .into_inner()
+                .flatten()
+                .any(|x| x.as_rule() == Rule::import_stmt)
+    });
+
+    let items = formatter.items(stmts, 0, source.code.len());
+    formatter.body(items, 0, Scope::Top(!fixed));
+
+    Ok(formatter.out)
+}
+
+/// Find every comment in a piece of code, skipping anything that looks like one inside a string
+/// or command substitution
+fn scan_comments(code: &str) -> Vec<Comment<'_>> {
+    let mut comments = vec![];
+    let mut idx = 0;
+
+    while idx < code.len() {
+        let rest = &code[idx..];
+
+        idx = if rest.starts_with("r\"\"\"") {
+            skip_string(code, idx + 4, "\"\"\"", false)
+        } else if rest.starts_with("\"\"\"") {
+            skip_string(code, idx + 3, "\"\"\"", true)
+        } else if rest.starts_with("r\"") {
+            skip_string(code, idx + 2, "\"", false)
+        } else if rest.starts_with('"') {
+            skip_string(code, idx + 1, "\"", true)
+        } else if rest.starts_with("$(") {
+            skip_command(code, idx + 2)
+        } else if rest.starts_with('#') || rest.starts_with("//") {
+            let end = rest.find('\n').map_or(code.len(), |x| idx + x);
+            let text = code[idx..end].trim_end();
+            comments.push(Comment {
+                start: idx,
+                end: idx + text.len(),
+                text,
+            });
+            end
+        } else if rest.starts_with(is_name_char) {
+            // names can contain slashes, so they need to be skipped whole
+            rest.find(|x| !is_name_char(x))
+                .map_or(code.len(), |x| idx + x)
+        } else {
+            idx + rest.chars().next().map_or(1, char::len_utf8)
+        };
+    }
+
+    comments
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "_-/:".contains(ch)
+}
+
+/// Return the index just past the end of a string that starts at `idx`
+fn skip_string(code: &str, mut idx: usize, delim: &str, escapes: bool) -> usize {
+    while idx < code.len() {
+        let rest = &code[idx..];
+
+        if rest.starts_with(delim) {
+            return idx + delim.len();
+        }
+
+        let mut chars = rest.chars();
+        let ch = chars.next().unwrap();
+        idx += ch.len_utf8();
+
+        if escapes && ch == '\\' {
+            idx += chars.next().map_or(0, char::len_utf8);
+        }
+    }
+
+    idx
+}
+
+/// Return the index just past the end of a command substitution that starts at `idx`
+fn skip_command(code: &str, mut idx: usize) -> usize {
+    let mut depth = 1;
+
+    for ch in code[idx..].chars() {
+        idx += ch.len_utf8();
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+
+        if depth == 0 {
+            break;
+        }
+    }
+
+    idx
+}
+
+/// Where a body appears, which decides which statements get moved to its top
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    /// The top level of a moldfile, and whether imports can be moved
+    Top(bool),
+    Group,
+    Recipe,
+    Block,
+}
+
+impl Scope {
+    fn rank(self, rule: Rule) -> usize {
+        match (self, rule) {
+            (Scope::Top(_), Rule::version_stmt) => 0,
+            (Scope::Top(true), Rule::import_stmt) => 1,
+            (Scope::Recipe, Rule::help_stmt) => 0,
+            _ => 2,
+        }
+    }
+}
+
+/// Where a statement actually ends
+///
+/// Rules that end with an optional piece also consume any whitespace and comments after them,
+/// so their span can't be trusted.
+fn statement_end(pair: &Pair<Rule>) -> usize {
+    match pair.as_rule() {
+        Rule::recipe_stmt
+        | Rule::group_stmt
+        | Rule::if_stmt
+        | Rule::elif_stmt
+        | Rule::else_stmt => pair.as_span().end(),
+        _ => pair
+            .clone()
+            .into_inner()
+            .last()
+            .map_or(pair.as_span().end(), |x| x.as_span().end()),
+    }
+}
+
+fn is_block(rule: Rule) -> bool {
+    rule == Rule::recipe_stmt || rule == Rule::group_stmt || rule == Rule::if_block
+}
+
+struct Formatter<'a> {
+    code: &'a str,
+    comments: Vec<Comment<'a>>,
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    /// Whether there's a blank line between two points in the code
+    fn blank(&self, start: usize, end: usize) -> bool {
+        self.code[start..end].matches('\n').count() >= 2
+    }
+
+    /// Interleave a body's statements with the comments between `start` and `end`
+    ///
+    /// Comments inside a block statement are left for that block's own body, but comments inside
+    /// any other statement (eg, between arguments) are moved above it.
+    fn items(&self, stmts: Vec<Pair<'a, Rule>>, start: usize, end: usize) -> Vec<Item<'a>> {
+        let mut items = vec![];
+        let mut pos = start;
+        let mut comments = self
+            .comments
+            .iter()
+            .filter(|x| x.start >= start && x.end <= end)
+            .peekable();
+
+        for pair in stmts {
+            let span = pair.as_span();
+
+            while let Some(comment) = comments.next_if(|x| x.start < span.start()) {
+                items.push(Item {
+                    blank: self.blank(pos, comment.start),
+                    entry: Entry::Comment(comment.text),
+                });
+                pos = comment.end;
+            }
+
+            let mut inner = vec![];
+            while let Some(comment) = comments.next_if(|x| x.start < statement_end(&pair)) {
+                inner.push(comment);
+            }
+
+            let mut blank = self.blank(pos, span.start());
+            if !is_block(pair.as_rule()) {
+                for comment in inner {
+                    items.push(Item {
+                        blank,
+                        entry: Entry::Comment(comment.text),
+                    });
+                    blank = false;
+                }
+            }
+
+            pos = statement_end(&pair);
+            let trailing = comments.next_if(|x| !self.code[pos..x.start].contains('\n'));
+            if let Some(comment) = trailing {
+                pos = comment.end;
+            }
+
+            items.push(Item {
+                blank,
+                entry: Entry::Statement(pair, trailing.map(|x| x.text)),
+            });
+        }
+
+        for comment in comments {
+            items.push(Item {
+                blank: self.blank(pos, comment.start),
+                entry: Entry::Comment(comment.text),
+            });
+            pos = comment.end;
+        }
+
+        items
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        for _ in 0..depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// Write out a body, moving statements whose position doesn't matter to the top
+    fn body(&mut self, items: Vec<Item<'a>>, depth: usize, scope: Scope) {
+        let mut chunks = vec![];
+        let mut current = vec![];
+
+        for item in items {
+            let rank = match &item.entry {
+                Entry::Statement(pair, _) => Some(scope.rank(pair.as_rule())),
+                Entry::Comment(_) => None,
+            };
+            current.push(item);
+
+            if let Some(rank) = rank {
+                chunks.push(Chunk {
+                    items: std::mem::take(&mut current),
+                    rank,
+                });
+            }
+        }
+
+        // comments at the end of a body stay at the end
+        if !current.is_empty() {
+            chunks.push(Chunk {
+                items: current,
+                rank: usize::MAX,
+            });
+        }
+
+        chunks.sort_by_key(|x| x.rank);
+
+        let mut prev: Option<&Chunk> = None;
+        for chunk in &chunks {
+            for (idx, item) in chunk.items.iter().enumerate() {
+                let blank = match (idx, prev) {
+                    (_, None) if idx == 0 => false,
+                    (0, Some(prev)) => {
+                        // blocks at the top of a file or group, and the version and imports at the
+                        // top of a file, are always set apart from their neighbours
+                        let spaced = (depth == 0 || scope == Scope::Group)
+                            && (prev.rule().is_some_and(is_block)
+                                || chunk.rule().is_some_and(is_block)
+                                || (prev.rank < 2 && chunk.rank == 2));
+                        item.blank || spaced
+                    }
+                    _ => item.blank,
+                };
+
+                if blank {
+                    self.out.push('\n');
+                }
+
+                match &item.entry {
+                    Entry::Comment(text) => self.line(depth, text),
+                    Entry::Statement(pair, trailing) => {
+                        self.statement(pair.clone(), depth, *trailing)
+                    }
+                }
+            }
+
+            prev = Some(chunk);
+        }
+    }
+
+    /// Write out a single statement, including the bodies of blocks
+    fn statement(&mut self, pair: Pair<'a, Rule>, depth: usize, trailing: Option<&str>) {
+        let trailing = trailing.map_or_else(String::new, |x| format!(" {}", x));
+
+        match pair.as_rule() {
+            Rule::recipe_stmt => {
+                let span = pair.as_span();
+                let mut keyword = String::new();
+                for part in pair.clone().into_inner() {
+                    match part.as_rule() {
+                        Rule::private | Rule::modifier => {
+                            keyword.push_str(part.as_str());
+                            keyword.push(' ');
+                        }
+                        _ => break,
+                    }
+                }
+                keyword.push_str("recipe");
+                let (header, stmts) = split_block(pair);
+                let items = self.items(stmts, span.start(), span.end());
+                self.line(depth, &format!("{} {} {{", keyword, header.join("")));
+                self.body(items, depth + 1, Scope::Recipe);
+                self.line(depth, &format!("}}{}", trailing));
+            }
+
+            Rule::group_stmt => {
+                let span = pair.as_span();
+                let (header, stmts) = split_block(pair);
+                let items = self.items(stmts, span.start(), span.end());
+                self.line(depth, &format!("group {} {{", header.join("")));
+                self.body(items, depth + 1, Scope::Group);
+                self.line(depth, &format!("}}{}", trailing));
+            }
+
+            Rule::if_block => {
+                let mut start = pair.as_span().start();
+                let cases: Vec<_> = pair.into_inner().collect();
+                let last = cases.len() - 1;
+
+                for (idx, case) in cases.into_iter().enumerate() {
+                    let mut end = case.as_span().end();
+
+                    // a comment right after a closing brace that's followed by another case
+                    // belongs to the case it closes, but one after the next case's opening brace
+                    // belongs to that case instead
+                    if idx < last {
+                        let comment = self
+                            .comments
+                            .iter()
+                            .find(|x| x.start >= end && self.code[end..x.start].trim().is_empty());
+                        if let Some(comment) = comment {
+                            end = comment.end;
+                        }
+                    }
+
+                    let keyword = match case.as_rule() {
+                        Rule::if_stmt => "if ",
+                        Rule::elif_stmt => "elif ",
+                        _ => "else",
+                    };
+                    let close = if idx == 0 { "" } else { "} " };
+                    let (header, stmts) = split_block(case);
+                    self.line(
+                        depth,
+                        &format!("{}{}{} {{", close, keyword, header.join("")),
+                    );
+
+                    let items = self.items(stmts, start, end);
+                    self.body(items, depth + 1, Scope::Block);
+                    start = end;
+                }
+
+                self.line(depth, &format!("}}{}", trailing));
+            }
+
+            Rule::import_stmt => {
+                let mut text = String::from("import");
+                for part in pair.into_inner() {
+                    if part.as_rule() == Rule::name {
+                        text.push_str(" as");
+                    }
+                    text.push(' ');
+                    text.push_str(&piece(part));
+                }
+
+                self.line(depth, &format!("{}{}", text, trailing));
+            }
+
+            rule => {
+                let pieces: Vec<_> = pair.into_inner().map(piece).collect();
+                let text = match rule {
+                    Rule::version_stmt => format!("version {}", pieces[0]),
+                    Rule::include_stmt => with_alias(format!("include {}", pieces[0]), &pieces),
+                    Rule::alias_stmt => format!("alias {} = {}", pieces[0], pieces[1]),
+                    Rule::dir_stmt => format!("dir {}", pieces[0]),
+                    Rule::shell_stmt => format!("shell {}", pieces[0]),
+                    Rule::help_stmt => format!("help {}", pieces[0]),
+                    Rule::run_stmt if pieces.len() == 2 => format!("-$ {}", pieces[1]),
+                    Rule::run_stmt => format!("$ {}", pieces[0]),
+                    Rule::ignore_errors_stmt => "ignore_errors".into(),
+                    Rule::allowed_exit_stmt => format!("allowed_exit [{}]", pieces.join(", ")),
+                    Rule::super_stmt => "super".into(),
+                    Rule::hook_stmt => pieces.join(" "),
+                    Rule::runtime_stmt => format!("runtime {}", pieces.join(" ")),
+                    Rule::script_stmt => format!("script {}", pieces.join(" ")),
+                    Rule::file_stmt => with_alias(format!("file {}", pieces[0]), &pieces),
+                    Rule::var_stmt => format!("var {} = {}", pieces[0], pieces[1]),
+                    Rule::default_stmt => format!("var {} := {}", pieces[0], pieces[1]),
+                    Rule::unset_stmt => format!("unset {}", pieces[0]),
+                    Rule::require_stmt => format!("require {}", pieces.join("")),
+                    Rule::default_target_stmt if pieces.len() == 1 => {
+                        format!("default {}", pieces[0])
+                    }
+                    Rule::default_target_stmt => format!("default [{}]", pieces.join(", ")),
+                    _ => unreachable!(),
+                };
+
+                self.line(depth, &format!("{}{}", text, trailing));
+            }
+        }
+    }
+}
+
+/// Append ` as <alias>` if a statement has a second piece
+fn with_alias(text: String, pieces: &[String]) -> String {
+    match pieces.get(1) {
+        Some(alias) => format!("{} as {}", text, alias),
+        None => text,
+    }
+}
+
+/// Split a block's Pair into its rendered header pieces and its body statements
+fn split_block(pair: Pair<Rule>) -> (Vec<String>, Vec<Pair<Rule>>) {
+    let inner = pair
+        .into_inner()
+        .filter(|x| x.as_rule() != Rule::private && x.as_rule() != Rule::modifier);
+    let (header, stmts): (Vec<_>, Vec<_>) = inner.partition(|x| {
+        matches!(
+            x.as_rule(),
+            Rule::params
+                | Rule::name
+                | Rule::or_expr
+                | Rule::and_expr
+                | Rule::not_expr
+                | Rule::atom
+        )
+    });
+
+    (header.into_iter().map(piece).collect(), stmts)
+}
+
+/// Render any part of a statement that isn't itself a statement
+///
+/// Strings and command substitutions are kept exactly as written, since their contents are
+/// significant.
+fn piece(pair: Pair<Rule>) -> String {
+    let rule = pair.as_rule();
+    let pieces = || pair.clone().into_inner().map(piece).collect::<Vec<_>>();
+
+    match rule {
+        Rule::params | Rule::args => format!("({})", pieces().join(", ")),
+        Rule::param | Rule::arg => pieces().join(" = "),
+        Rule::or_expr => pieces().join(" | "),
+        Rule::and_expr => pieces().join(" + "),
+        Rule::not_expr => format!("~{}", pieces().join("")),
+        Rule::group => format!("({})", pieces().join("")),
+        Rule::atom | Rule::target => pieces().join(""),
+        Rule::only_list => format!("only [{}]", pieces().join(", ")),
+        Rule::with_block if pieces().is_empty() => "with {}".into(),
+        Rule::with_block => format!("with {{ {} }}", pieces().join(", ")),
+        Rule::with_var => pieces().join(" = "),
+        Rule::except_list => format!("except [{}]", pieces().join(", ")),
+        Rule::compare => pieces().join(" "),
+        Rule::defined => format!("defined({})", pieces().join("")),
+        Rule::predicate => {
+            let pieces = pieces();
+            format!("{}({})", pieces[0], pieces[1])
+        }
+        _ => pair.as_str().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fmt(code: &str) -> String {
+        let source = Source {
+            code,
+            path: Path::new("moldfile"),
+        };
+        format(&source).unwrap()
+    }
+
+    /// Format some code and make sure that formatting the result doesn't change it again
+    fn round_trip(code: &str) -> String {
+        let out = fmt(code);
+        assert_eq!(fmt(&out), out);
+        out
+    }
+
+    #[test]
+    fn formats_a_whole_moldfile() {
+        let code = r##"# top comment
+version "0.7"
+var A = "x"   # trailing
+var B := $(echo hi)
+import "https://example.com/x.git" as x/ only [a, b] with { C = "d" }
+group g { recipe a(p, q = "1") {
+  help r"""
+    raw $A
+  """
+  require b("2")
+  -$ "echo $p"
+  before "echo hi"
+  }
+  // slash comment
+  recipe b(v) { script "python" """
+      print(1)
+  """ }
+}
+if linux + ~mac { alias z = g/a }
+else { default g/a }
+"##;
+        let expected = r##"# top comment
+version "0.7"
+import "https://example.com/x.git" as x/ only [a, b] with { C = "d" }
+
+var A = "x" # trailing
+var B := $(echo hi)
+
+group g {
+  recipe a(p, q = "1") {
+    help r"""
+    raw $A
+  """
+    require b("2")
+    -$ "echo $p"
+    before "echo hi"
+  }
+
+  // slash comment
+  recipe b(v) {
+    script "python" """
+      print(1)
+  """
+  }
+}
+
+if linux + ~mac {
+  alias z = g/a
+} else {
+  default g/a
+}
+"##;
+        assert_eq!(round_trip(code), expected);
+    }
+
+    #[test]
+    fn comment_after_closing_brace_stays_with_its_case() {
+        let code = "version \"0.7\"\nif linux {\n  $ \"a\"\n} # linux\nelse {\n  $ \"b\"\n}\n";
+        assert_eq!(
+            round_trip(code),
+            "version \"0.7\"\n\nif linux {\n  $ \"a\"\n  # linux\n} else {\n  $ \"b\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn comment_after_opening_brace_stays_with_its_case() {
+        let code = "version \"0.7\"\nif linux {\n  $ \"a\"\n} elif mac { # mac\n  $ \"b\"\n}\n";
+        assert_eq!(
+            round_trip(code),
+            "version \"0.7\"\n\nif linux {\n  $ \"a\"\n} elif mac {\n  # mac\n  $ \"b\"\n}\n"
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use super::remote;
-use failure::err_msg;
 use failure::format_err;
 use failure::Error;
 use pest::iterators::Pair;
 use pest::iterators::Pairs;
 use pest::Parser;
 use pest_derive::Parser;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -15,18 +17,136 @@ pub enum Expr {
     Not(Box<Expr>),
     Group(Box<Expr>),
     Atom(String),
+    Compare(Operand, CompareOp, Operand),
+    Defined(String),
+    Predicate(Predicate, String),
     Wild,
 }
 
+/// One side of a comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Var(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Match,
+}
+
+/// A check against the world outside of mold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predicate {
+    /// A path exists, relative to the moldfile's directory
+    Exists,
+
+    /// A path is a directory, relative to the moldfile's directory
+    IsDir,
+
+    /// A command can be found on the $PATH
+    HasCmd,
+
+    /// A variable is set in the environment mold was run in
+    Env,
+}
+
+/// Everything a conditional expression can be checked against
+pub struct Context<'a> {
+    /// A set of currently active environments
+    pub envs: &'a super::EnvSet,
+
+    /// A map of variables defined so far
+    pub vars: super::ValueMap,
+
+    /// Directory of the moldfile being compiled
+    pub dir: &'a Path,
+}
+
+impl Context<'_> {
+    /// Look up a variable's value, falling back to the process environment
+    fn var(&self, name: &str) -> Option<String> {
+        match self.vars.get(name) {
+            Some(value) => value.clone(),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    /// Resolve an Operand to a string, treating undefined variables as empty
+    fn value(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Var(name) => self.var(name).unwrap_or_default(),
+            Operand::Literal(value) => value.clone(),
+        }
+    }
+
+    /// Perform variable expansion on a predicate's argument
+    fn expand(&self, value: &str) -> String {
+        shellexpand::env_with_context_no_errors(value, |name| {
+            Some(self.var(name).unwrap_or_default())
+        })
+        .into()
+    }
+}
+
 impl Expr {
-    pub fn apply(&self, to: &super::EnvSet) -> bool {
-        match self {
-            Expr::And(x, y) => x.apply(to) && y.apply(to),
-            Expr::Or(x, y) => x.apply(to) || y.apply(to),
-            Expr::Not(x) => !x.apply(to),
-            Expr::Group(x) => x.apply(to),
-            Expr::Atom(x) => to.contains(x),
+    pub fn apply(&self, to: &Context) -> Result<bool, Error> {
+        Ok(match self {
+            Expr::And(x, y) => x.apply(to)? && y.apply(to)?,
+            Expr::Or(x, y) => x.apply(to)? || y.apply(to)?,
+            Expr::Not(x) => !x.apply(to)?,
+            Expr::Group(x) => x.apply(to)?,
+            Expr::Atom(x) => to.envs.contains(x),
+            Expr::Compare(lhs, op, rhs) => {
+                let lhs = to.value(lhs);
+                let rhs = to.value(rhs);
+                match op {
+                    CompareOp::Equal => lhs == rhs,
+                    CompareOp::NotEqual => lhs != rhs,
+                    CompareOp::Match => regex::Regex::new(&rhs)
+                        .map_err(|err| format_err!("Invalid regex {}: {}", rhs, err))?
+                        .is_match(&lhs),
+                }
+            }
+            Expr::Defined(x) => to.var(x).is_some(),
+            Expr::Predicate(predicate, arg) => {
+                let arg = to.expand(arg);
+                match predicate {
+                    Predicate::Exists => to.dir.join(arg).exists(),
+                    Predicate::IsDir => to.dir.join(arg).is_dir(),
+                    Predicate::HasCmd => super::util::find_command(&arg).is_some(),
+                    Predicate::Env => std::env::var_os(arg).is_some(),
+                }
+            }
             Expr::Wild => true,
+        })
+    }
+
+    /// Collect the names of every variable applying this Expr needs to know about
+    pub fn vars(&self, names: &mut super::NameSet) {
+        match self {
+            Expr::And(x, y) | Expr::Or(x, y) => {
+                x.vars(names);
+                y.vars(names);
+            }
+            Expr::Not(x) | Expr::Group(x) => x.vars(names),
+            Expr::Compare(lhs, _, rhs) => {
+                for operand in &[lhs, rhs] {
+                    match operand {
+                        Operand::Var(name) => {
+                            names.insert(name.clone());
+                        }
+                        Operand::Literal(text) => names.extend(var_refs(text)),
+                    }
+                }
+            }
+            Expr::Defined(name) => {
+                names.insert(name.clone());
+            }
+            Expr::Predicate(_, arg) => names.extend(var_refs(arg)),
+            Expr::Atom(_) | Expr::Wild => {}
         }
     }
 
@@ -50,6 +170,32 @@ impl Expr {
                 And(lhs.into(), rhs.into())
             }
 
+            compare => {
+                let mut inner = pair.into_inner();
+                let lhs = consume_operand(&mut inner).unwrap();
+                let op = match inner.next().unwrap().as_str() {
+                    "==" => CompareOp::Equal,
+                    "!=" => CompareOp::NotEqual,
+                    _ => CompareOp::Match,
+                };
+                let rhs = consume_operand(&mut inner).unwrap();
+                Compare(lhs, op, rhs)
+            }
+
+            defined => Defined(pair.into_inner().next().unwrap().as_str().into()),
+
+            predicate => {
+                let mut inner = pair.into_inner();
+                let kind = match inner.next().unwrap().as_str() {
+                    "exists" => self::Predicate::Exists,
+                    "is_dir" => self::Predicate::IsDir,
+                    "has_cmd" => self::Predicate::HasCmd,
+                    _ => self::Predicate::Env,
+                };
+                let path_or_name = consume_string(&mut inner).unwrap();
+                Predicate(kind, path_or_name)
+            }
+
             not_expr => Not(single_expr(pair).into()),
             atom | group => single_expr(pair),
             name => Atom(pair.as_str().into()),
@@ -59,23 +205,131 @@ impl Expr {
     }
 }
 
-// FIXME inline scripts?
+/// A byte range in a moldfile's code
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        Span {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+/// A moldfile's code and path, used to point errors at the code that caused them
+pub struct Source<'a> {
+    pub code: &'a str,
+    pub path: &'a Path,
+}
+
+impl Source<'_> {
+    /// Directory that the moldfile lives in
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("/"))
+    }
+
+    /// Create an error that shows the file, line, column and first line of a Span
+    pub fn error(&self, span: Span, message: String) -> Error {
+        let end = self.code[span.start..span.end]
+            .find('\n')
+            .map_or(span.end, |idx| span.start + idx);
+        let span = pest::Span::new(self.code, span.start, end).unwrap();
+
+        pest::error::Error::new_from_span(
+            pest::error::ErrorVariant::<Rule>::CustomError { message },
+            span,
+        )
+        .with_path(&self.path.display().to_string())
+        .into()
+    }
+}
+
+/// A single statement and where it came from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub enum Statement {
+pub enum StatementKind {
+    Alias(String, String),
     Dir(String),
+    Shell(String),
     Help(String),
+    Hook(super::Hook, String),
     IfBlock(Vec<Statement>),
     If(Expr, Vec<Statement>),
     Else(Vec<Statement>),
-    Import(String, Option<String>),
-    Recipe(String, Vec<Statement>),
-    Require(String),
-    Run(String),
-    Var(String, String),
-    Default(String, String),
+    File(String, Option<String>),
+    Group(String, Vec<Statement>),
+    Import(
+        String,
+        Option<String>,
+        super::Selection,
+        Option<super::VarMap>,
+    ),
+    Include(String, Option<String>),
+    Recipe(
+        String,
+        Vec<super::Param>,
+        Vec<Statement>,
+        bool,
+        super::Modifier,
+    ),
+    Require(super::Target),
+    Run(String, bool),
+    IgnoreErrors,
+    AllowedExit(Vec<i32>),
+    Super,
+    Runtime(String, String, String),
+    Script(String, String),
+    Var(String, super::Var),
+    Default(String, super::Var),
+    DefaultTarget(Vec<super::Target>),
     Version(String),
 }
 
+impl StatementKind {
+    /// The keyword used to write this kind of statement
+    pub fn keyword(&self) -> &'static str {
+        use StatementKind::*;
+
+        match self {
+            Alias(..) => "alias",
+            Dir(_) => "dir",
+            Shell(_) => "shell",
+            Help(_) => "help",
+            Hook(super::Hook::Before, _) => "before",
+            Hook(super::Hook::After, _) => "after",
+            Hook(super::Hook::Finally, _) => "finally",
+            IfBlock(_) | If(..) => "if",
+            Else(_) => "else",
+            File(..) => "file",
+            Group(..) => "group",
+            Import(..) => "import",
+            Include(..) => "include",
+            Recipe(..) => "recipe",
+            Require(_) => "require",
+            Run(..) => "run",
+            IgnoreErrors => "ignore_errors",
+            AllowedExit(_) => "allowed_exit",
+            Super => "super",
+            Runtime(..) => "runtime",
+            Script(..) => "script",
+            Var(_, super::Var::Unset) => "unset",
+            Var(..) | Default(..) => "var",
+            DefaultTarget(_) => "default",
+            Version(_) => "version",
+        }
+    }
+}
+
 impl Statement {
     /// Given a Pair, convert it into a Statement
     ///
@@ -87,67 +341,188 @@ impl Statement {
     /// unexpected ways.
     pub fn from(pair: Pair<Rule>) -> Self {
         use Rule::*; // a consequence is that no variables can shadow one of these
-        use Statement::*;
+        use StatementKind::*;
 
-        match pair.as_rule() {
-            if_block | if_recipe_block => {
+        let span = pair.as_span().into();
+        let kind = match pair.as_rule() {
+            if_block => {
                 // This only works on statements produced by parsing the grammar, because the grammar
                 // enforces that the block will follow an if..elif*..else? sequence. Consequently,
                 // processing the sequence can just iterate in-order and break on the first match. See the
-                // `flatten` function below for more information.
+                // `select` function below for more information.
                 let mut inner = pair.into_inner();
                 let pieces = consume_statements(&mut inner);
                 IfBlock(pieces)
             }
 
-            if_stmt | if_recipe_stmt | elif_stmt | elif_recipe_stmt => {
+            if_stmt | elif_stmt => {
                 let mut inner = pair.into_inner();
                 let cond = consume_expr(&mut inner).unwrap();
-                let body = consume_statements(&mut inner);
-                If(cond, body)
+                let stmts = consume_statements(&mut inner);
+                If(cond, stmts)
             }
 
-            else_stmt | else_recipe_stmt => {
+            else_stmt => {
                 let mut inner = pair.into_inner();
-                let body = consume_statements(&mut inner);
-                Else(body)
+                let stmts = consume_statements(&mut inner);
+                Else(stmts)
             }
 
             import_stmt => {
                 let mut inner = pair.into_inner();
-                let source = consume_string(&mut inner).unwrap();
+                let source = consume_literal(&mut inner).unwrap();
+                let dep_name = match inner.peek() {
+                    Some(x) if x.as_rule() == name => consume_name(&mut inner),
+                    _ => None,
+                };
+                let mut selection = super::Selection::All;
+                let mut with_vars = None;
+                for x in inner {
+                    let rule = x.as_rule();
+                    let inner = x.into_inner();
+                    match rule {
+                        only_list => selection = super::Selection::Only(consume_names(inner)),
+                        except_list => selection = super::Selection::Except(consume_names(inner)),
+                        _ => {
+                            let vars = with_vars.get_or_insert_with(super::VarMap::new);
+                            for var in inner {
+                                let mut var_inner = var.into_inner();
+                                let var_name = consume_name(&mut var_inner).unwrap();
+                                let value = consume_var(&mut var_inner).unwrap();
+                                vars.insert(var_name, value);
+                            }
+                        }
+                    }
+                }
+                Import(source, dep_name, selection, with_vars)
+            }
+
+            include_stmt => {
+                let mut inner = pair.into_inner();
+                let pattern = consume_literal(&mut inner).unwrap();
                 let dep_name = consume_name(&mut inner);
-                Import(source, dep_name)
+                Include(pattern, dep_name)
+            }
+
+            file_stmt => {
+                let mut inner = pair.into_inner();
+                let path = consume_string(&mut inner).unwrap();
+                let runtime = consume_string(&mut inner);
+                File(path, runtime)
             }
 
             recipe_stmt => {
                 let mut inner = pair.into_inner();
+                let is_private = inner.peek().is_some_and(|x| x.as_rule() == private);
+                if is_private {
+                    inner.next();
+                }
+                let rec_modifier = match inner.peek() {
+                    Some(x) if x.as_rule() == modifier => match inner.next().unwrap().as_str() {
+                        "override" => super::Modifier::Override,
+                        _ => super::Modifier::Extend(0),
+                    },
+                    _ => super::Modifier::Plain,
+                };
                 let rec_name = consume_name(&mut inner).unwrap();
+                let rec_params = consume_params(&mut inner);
+                let stmts = consume_statements(&mut inner);
+                Recipe(rec_name, rec_params, stmts, is_private, rec_modifier)
+            }
+
+            group_stmt => {
+                let mut inner = pair.into_inner();
+                let group_name = consume_name(&mut inner).unwrap();
                 let stmts = consume_statements(&mut inner);
-                Recipe(rec_name, stmts)
+                Group(group_name, stmts)
+            }
+
+            alias_stmt => {
+                let mut inner = pair.into_inner();
+                let alias_name = consume_name(&mut inner).unwrap();
+                let recipe_name = consume_name(&mut inner).unwrap();
+                Alias(alias_name, recipe_name)
+            }
+
+            require_stmt => {
+                let mut inner = pair.into_inner();
+                let target_name = consume_name(&mut inner).unwrap();
+                let target_args = consume_args(&mut inner);
+                Require(super::Target {
+                    name: target_name,
+                    args: target_args,
+                })
+            }
+
+            default_target_stmt => DefaultTarget(pair.into_inner().map(target_value).collect()),
+
+            runtime_stmt => {
+                let mut inner = pair.into_inner();
+                let runtime_name = consume_name(&mut inner).unwrap();
+                let command = consume_string(&mut inner).unwrap();
+                let extension = consume_literal(&mut inner).unwrap();
+                Runtime(runtime_name, command, extension)
+            }
+
+            script_stmt => {
+                let mut inner = pair.into_inner();
+                let runtime = consume_string(&mut inner).unwrap();
+                let code = consume_literal(&mut inner).unwrap();
+                Script(runtime, code)
             }
 
             var_stmt => {
                 let mut inner = pair.into_inner();
                 let var_name = consume_name(&mut inner).unwrap();
-                let value = consume_string(&mut inner).unwrap();
+                let value = consume_var(&mut inner).unwrap();
                 Var(var_name, value)
             }
 
             default_stmt => {
                 let mut inner = pair.into_inner();
                 let var_name = consume_name(&mut inner).unwrap();
-                let value = consume_string(&mut inner).unwrap();
+                let value = consume_var(&mut inner).unwrap();
                 Default(var_name, value)
             }
 
+            unset_stmt => {
+                let mut inner = pair.into_inner();
+                let var_name = consume_name(&mut inner).unwrap();
+                Var(var_name, super::Var::Unset)
+            }
+
             dir_stmt => Dir(single_string(pair)),
-            help_stmt => Help(single_string(pair)),
-            require_stmt => Require(single_name(pair)),
-            run_stmt => Run(single_string(pair)),
-            version_stmt => Version(single_string(pair)),
+            shell_stmt => Shell(single_string(pair)),
+            help_stmt => Help(single_literal(pair)),
+            run_stmt => {
+                let mut inner = pair.into_inner();
+                let is_tolerant = inner.peek().is_some_and(|x| x.as_rule() == tolerant);
+                if is_tolerant {
+                    inner.next();
+                }
+                Run(consume_string(&mut inner).unwrap(), is_tolerant)
+            }
+            ignore_errors_stmt => IgnoreErrors,
+            allowed_exit_stmt => AllowedExit(
+                pair.into_inner()
+                    .map(|x| x.as_str().parse().unwrap())
+                    .collect(),
+            ),
+            super_stmt => Super,
+            hook_stmt => {
+                let mut inner = pair.into_inner();
+                let hook_kind = match inner.next().unwrap().as_str() {
+                    "before" => super::Hook::Before,
+                    "after" => super::Hook::After,
+                    _ => super::Hook::Finally,
+                };
+                Hook(hook_kind, consume_string(&mut inner).unwrap())
+            }
+            version_stmt => Version(single_literal(pair)),
             _ => unreachable!(),
-        }
+        };
+
+        Statement { kind, span }
     }
 }
 
@@ -155,18 +530,85 @@ impl Statement {
 #[grammar = "mold.pest"]
 struct MoldParser;
 
-/// Given a Pairs iterator, try to yank a `string` out of it
+/// Given a Pairs iterator, try to yank a `string` that will be variable expanded out of it
 fn consume_string(pairs: &mut Pairs<Rule>) -> Option<String> {
-    pairs
-        .next()
-        .and_then(|x| x.into_inner().next())
-        .map(|x| unescape(x.as_str()))
+    pairs.next().map(|x| string_value(x, false))
+}
+
+/// Given a Pairs iterator, try to yank a `string` that's used verbatim out of it
+fn consume_literal(pairs: &mut Pairs<Rule>) -> Option<String> {
+    pairs.next().map(|x| string_value(x, true))
+}
+
+/// Given a Pairs iterator, try to yank a `string` or `command_sub` out of it
+fn consume_var(pairs: &mut Pairs<Rule>) -> Option<super::Var> {
+    pairs.next().map(|x| match x.as_rule() {
+        Rule::command_sub => super::Var::Command(inner_text(x)),
+        _ => super::Var::Value(string_value(x, false)),
+    })
+}
+
+/// Given a Pair, take the raw text of its single inner Pair
+fn inner_text(pair: Pair<Rule>) -> String {
+    pair.into_inner().next().unwrap().as_str().to_string()
+}
+
+/// Given a `string` Pair, convert it into a String
+///
+/// Triple-quoted strings are dedented before escapes are processed. Raw strings
+/// skip escape processing entirely, and unless `literal` is set, their `$`s are
+/// doubled so that variable expansion turns them back into a single `$`.
+fn string_value(pair: Pair<Rule>, literal: bool) -> String {
+    let inner = pair.into_inner().next().unwrap();
+    let text = match inner.as_rule() {
+        Rule::long_chars | Rule::raw_long_chars => dedent(inner.as_str()),
+        _ => inner.as_str().to_string(),
+    };
+
+    match inner.as_rule() {
+        Rule::chars | Rule::long_chars => unescape(&text),
+        _ if literal => text,
+        _ => text.replace('$', "$$"),
+    }
+}
+
+/// Given the contents of a triple-quoted string, strip its common indentation
+///
+/// A leading newline directly after the opening quotes and a trailing line of
+/// only whitespace before the closing quotes are both removed, so the quotes can
+/// sit on their own lines.
+fn dedent(source: &str) -> String {
+    let source = source
+        .strip_prefix("\r\n")
+        .or_else(|| source.strip_prefix('\n'))
+        .unwrap_or(source);
+
+    let mut lines: Vec<_> = source.lines().collect();
+    if source.ends_with('\n') {
+        lines.push("");
+    }
+    if lines.len() > 1 && lines.last().is_some_and(|x| x.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or_else(|| line.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 /// Given a &str, unescape special characters
 ///
-/// This should potentially return an error, but that really complicates the
-/// above API, so it just silently permits and ignores invalid escapes. Oops.
+/// Invalid escapes are rejected by `validate_escapes` before any Statements are
+/// built, so this can assume that every escape is valid.
 fn unescape(source: &str) -> String {
     let mut new = String::with_capacity(source.len());
     let mut chars = source.chars();
@@ -194,6 +636,75 @@ fn consume_name(pairs: &mut Pairs<Rule>) -> Option<String> {
     pairs.next().map(|x| x.as_str().to_string())
 }
 
+/// Given a Pairs iterator of `name`s, collect all of them
+fn consume_names(pairs: Pairs<Rule>) -> super::NameSet {
+    pairs.map(|x| x.as_str().to_string()).collect()
+}
+
+/// Given a Pairs iterator, try to yank a `params` list out of it
+///
+/// Recipes without a parameter list produce an empty Vec.
+fn consume_params(pairs: &mut Pairs<Rule>) -> Vec<super::Param> {
+    match pairs.peek() {
+        Some(x) if x.as_rule() == Rule::params => pairs
+            .next()
+            .unwrap()
+            .into_inner()
+            .map(|param| {
+                let mut inner = param.into_inner();
+                let name = consume_name(&mut inner).unwrap();
+                let default = consume_string(&mut inner);
+                super::Param { name, default }
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Given a Pairs iterator, try to yank an `args` list out of it
+///
+/// Values can be either a `string` or a bare `name`.
+fn consume_args(pairs: &mut Pairs<Rule>) -> Vec<super::Arg> {
+    let args = match pairs.next() {
+        Some(x) => x.into_inner(),
+        None => return vec![],
+    };
+
+    args.map(|arg| {
+        let mut inner: Vec<_> = arg.into_inner().map(arg_value).collect();
+        let value = inner.pop().unwrap();
+        match inner.pop() {
+            Some(name) => super::Arg::Named(name, value),
+            None => super::Arg::Positional(value),
+        }
+    })
+    .collect()
+}
+
+/// Given a `target` Pair, convert it into a Target
+fn target_value(pair: Pair<Rule>) -> super::Target {
+    let mut inner = pair.into_inner();
+    let name = consume_name(&mut inner).unwrap();
+    let args = consume_args(&mut inner);
+    super::Target { name, args }
+}
+
+/// Given a Pair that's either a `string` or a `name`, convert it into a String
+fn arg_value(pair: Pair<Rule>) -> String {
+    match pair.as_rule() {
+        Rule::string => string_value(pair, false),
+        _ => pair.as_str().to_string(),
+    }
+}
+
+/// Given a Pairs iterator, try to yank a `var_ref` or `string` out of it
+fn consume_operand(pairs: &mut Pairs<Rule>) -> Option<Operand> {
+    pairs.next().map(|x| match x.as_rule() {
+        Rule::var_ref => Operand::Var(inner_text(x)),
+        _ => Operand::Literal(string_value(x, true)),
+    })
+}
+
 /// Given a Pairs iterator, try to yank an `expr` out of it
 fn consume_expr(pairs: &mut Pairs<Rule>) -> Option<Expr> {
     pairs.next().map(Expr::from)
@@ -212,9 +723,9 @@ fn single_string(pair: Pair<Rule>) -> String {
     consume_string(&mut pair.into_inner()).unwrap()
 }
 
-/// Given a Pair, consume a single `name` from it
-fn single_name(pair: Pair<Rule>) -> String {
-    consume_name(&mut pair.into_inner()).unwrap()
+/// Given a Pair, consume a single verbatim `string` from it
+fn single_literal(pair: Pair<Rule>) -> String {
+    consume_literal(&mut pair.into_inner()).unwrap()
 }
 
 /// Given a Pair, consume a single `expr` from it
@@ -222,41 +733,173 @@ fn single_expr(pair: Pair<Rule>) -> Expr {
     consume_expr(&mut pair.into_inner()).unwrap()
 }
 
-/// Given a &str of mold lang code, convert it into a pest parse tree
-fn parse(code: &str) -> Result<Vec<Statement>, Error> {
-    let mut main = MoldParser::parse(Rule::main, code)?;
+/// Given a parse tree, make sure that every escape sequence in it is valid
+fn validate_escapes(source: &Source, pairs: Pairs<Rule>) -> Result<(), Error> {
+    let strings = pairs
+        .flatten()
+        .filter(|x| x.as_rule() == Rule::chars || x.as_rule() == Rule::long_chars);
+
+    for pair in strings {
+        let start = pair.as_span().start();
+        let mut chars = pair.as_str().char_indices();
+
+        while let Some((_, ch)) = chars.next() {
+            if ch != '\\' {
+                continue;
+            }
+
+            match chars.next() {
+                Some((_, '"')) | Some((_, '\\')) | Some((_, 'n')) | Some((_, 'r'))
+                | Some((_, 't')) => {}
+                Some((idx, ch2)) => {
+                    let span = Span {
+                        start: start + idx - 1,
+                        end: start + idx + ch2.len_utf8(),
+                    };
+                    let message = format!("invalid escape sequence \\{}", ch2);
+                    return Err(source.error(span, message));
+                }
+                None => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Describe a grammar rule for syntax errors
+fn rule_name(rule: &Rule) -> String {
+    use Rule::*;
+
+    match rule {
+        EOI => "end of file",
+        string | chars | long_chars | raw_chars | raw_long_chars => "string",
+        name => "name",
+        identifier => "variable name",
+        var_ref => "variable",
+        expr | or_expr | and_expr | not_expr | atom | group | wild | compare | defined
+        | predicate | predicate_name => "expression",
+        compare_op => "comparison",
+        params | param => "parameter list",
+        args | arg => "argument list",
+        command_sub | command_chars => "command",
+        dir_stmt => "dir",
+        shell_stmt => "shell",
+        help_stmt => "help",
+        if_block | if_stmt => "if",
+        elif_stmt => "elif",
+        else_stmt => "else",
+        import_stmt | only_list | except_list | with_block | with_var => "import",
+        include_stmt => "include",
+        recipe_stmt | private | modifier => "recipe",
+        super_stmt => "super",
+        alias_stmt => "alias",
+        group_stmt => "group",
+        require_stmt => "require",
+        default_target_stmt | target => "default",
+        run_stmt | tolerant => "run",
+        ignore_errors_stmt => "ignore_errors",
+        allowed_exit_stmt | exit_code => "allowed_exit",
+        hook_stmt | hook => "hook",
+        runtime_stmt => "runtime",
+        script_stmt => "script",
+        file_stmt => "file",
+        var_stmt | default_stmt => "var",
+        unset_stmt => "unset",
+        version_stmt => "version",
+        _ => return format!("{:?}", rule),
+    }
+    .into()
+}
+
+/// Given a moldfile's Source, convert it into a list of Statements
+pub fn parse(source: &Source) -> Result<Vec<Statement>, Error> {
+    let mut main = parse_tree(source)?;
     let stmts = consume_statements(&mut main);
     Ok(stmts)
 }
 
-/// Given a &str of code and an EnvSet, compile it into a Moldfile
-pub fn compile(code: &str, mold: &mut super::Mold) -> Result<super::Moldfile, Error> {
-    use Statement::*;
-    let statements = flatten(parse(code)?, &mold.envs)?;
+/// Given a moldfile's Source, parse it into the Pairs of its top-level statements
+pub fn parse_tree<'a>(source: &Source<'a>) -> Result<Pairs<'a, Rule>, Error> {
+    let main = MoldParser::parse(Rule::main, source.code).map_err(|mut err| {
+        // several rules share a description (eg, var and := statements both start with "var"),
+        // so only mention each description once
+        if let pest::error::ErrorVariant::ParsingError { positives, .. } = &mut err.variant {
+            let mut seen = vec![];
+            positives.retain(|rule| {
+                let name = rule_name(rule);
+                let first = !seen.contains(&name);
+                seen.push(name);
+                first
+            });
+        }
+        err.with_path(&source.path.display().to_string())
+            .renamed_rules(rule_name)
+    })?;
+    validate_escapes(source, main.clone())?;
+    Ok(main)
+}
+
+/// Given a moldfile's Source, compile it into a Moldfile
+///
+/// `seed` holds variables that the moldfile starts with, which its defaults won't replace.
+pub fn compile(
+    source: &Source,
+    mold: &mut super::Mold,
+    seed: super::VarMap,
+) -> Result<super::Moldfile, Error> {
+    use StatementKind::*;
+    let mut statements: VecDeque<_> = parse(source)?.into();
 
     let mut version = None;
     let mut dir = None;
+    let mut shell = None;
+    let mut hooks = super::Hooks::default();
+    let mut default_targets = vec![];
     let mut includes = super::IncludeVec::new();
+    let mut local_includes = vec![];
     let mut recipes = super::RecipeMap::new();
-    let mut vars = super::VarMap::new();
+    let mut aliases = super::AliasMap::new();
+    let mut vars = if mold.use_vars {
+        seed
+    } else {
+        super::VarMap::new()
+    };
+    let mut runtimes = super::RuntimeMap::new();
+
+    while let Some(Statement { kind, span }) = statements.pop_front() {
+        match kind {
+            // conditions are checked as they're reached, so that they can see the variables
+            // defined above them
+            IfBlock(cases) => {
+                let body = select(cases, source, &mold.envs, |names| {
+                    visible_vars(mold, &[(&vars, &super::NameSet::new())], names)
+                })?;
+                unroll(&mut statements, body);
+            }
 
-    for stmt in statements {
-        match stmt {
             Version(s) => {
                 if version.is_none() {
                     version = Some(s);
                 } else {
-                    return Err(format_err!("Duplicate version specified: {}", s));
+                    return Err(source.error(span, format!("Duplicate version specified: {}", s)));
                 }
             }
 
             Help(_) => {}
 
-            Import(url, prefix) => includes.push(super::Include {
+            Import(url, prefix, selection, with_vars) => includes.push(super::Include {
                 remote: remote::Remote::from_str(&url)?,
                 prefix: prefix.unwrap_or_else(|| "".to_string()),
+                selection,
+                vars: with_vars,
             }),
 
+            Include(pattern, prefix) => {
+                let prefix = prefix.unwrap_or_else(|| "".to_string());
+                local_includes.push((find_includes(source, span, &pattern)?, prefix));
+            }
+
             Var(name, value) => {
                 if mold.use_vars {
                     vars.insert(name, value);
@@ -273,45 +916,165 @@ pub fn compile(code: &str, mold: &mut super::Mold) -> Result<super::Moldfile, Er
                 }
             }
 
-            Recipe(name, body) => {
-                recipes.insert(name, compile_recipe(body, mold)?);
+            Runtime(name, command, extension) => {
+                runtimes.insert(name, super::Runtime { command, extension });
+            }
+
+            Recipe(name, params, body, private, modifier) => {
+                let mut recipe = compile_recipe(params, body, modifier, span, source, &vars, mold)?;
+                let base = name.rsplit('/').next().unwrap_or_default();
+                recipe.private = private || base.starts_with('_');
+
+                let recipe = match recipes.remove(&name) {
+                    Some(existing) => existing
+                        .merge(recipe, &name)
+                        .map_err(|err| source.error(span, err))?,
+                    None => recipe,
+                };
+                recipes.insert(name, recipe);
+            }
+
+            // groups are flattened into the top level by prefixing the names they define
+            Group(name, body) => {
+                let body = body
+                    .into_iter()
+                    .map(|stmt| ungroup(&name, stmt, source))
+                    .collect::<Result<_, _>>()?;
+                unroll(&mut statements, body);
+            }
+
+            Alias(name, recipe) => {
+                if aliases.contains_key(&name) {
+                    return Err(source.error(span, format!("Alias {} is already defined", name)));
+                }
+                aliases.insert(name, recipe);
             }
 
             Dir(path) => {
                 dir = Some(path);
             }
 
-            _ => unreachable!(),
+            Shell(cmd) => {
+                shell = Some(cmd);
+            }
+
+            Hook(hook, cmd) => {
+                hooks.push(hook, cmd);
+            }
+
+            DefaultTarget(targets) => {
+                default_targets = targets;
+            }
+
+            kind => {
+                return Err(source.error(
+                    span,
+                    format!("{} statements are only allowed in a recipe", kind.keyword()),
+                ))
+            }
         }
     }
 
-    let version = version.ok_or_else(|| err_msg("File version must be specified"))?;
+    // hooks and shells at the top of a moldfile apply to every recipe that it defines. shells are
+    // given to each line now, since an extended recipe will have lines from other moldfiles
+    for recipe in recipes.values_mut() {
+        recipe.hooks = hooks.clone().wrap(std::mem::take(&mut recipe.hooks));
+        let line_shell = recipe.shell.clone().or_else(|| shell.clone());
+        for line in recipe.lines_mut() {
+            line.shell = line_shell.clone();
+        }
+    }
+
+    // names used inside a group refer to the group's own recipes first
+    let defined: super::NameSet = recipes.keys().chain(aliases.keys()).cloned().collect();
+
+    for (name, recipe) in recipes.iter_mut() {
+        recipe.requires = recipe
+            .requires
+            .iter()
+            .map(|x| super::Target {
+                name: relative(name, &x.name, &defined),
+                args: x.args.clone(),
+            })
+            .collect();
+    }
+
+    for (name, recipe) in aliases.iter_mut() {
+        *recipe = relative(name, recipe, &defined);
+    }
+
+    let version = version.ok_or_else(|| {
+        source.error(
+            Span { start: 0, end: 0 },
+            "File version must be specified".into(),
+        )
+    })?;
 
     Ok(super::Moldfile {
         version,
         includes,
+        local_includes,
         recipes,
+        aliases,
         vars,
+        runtimes,
         dir,
+        default_targets,
     })
 }
 
-/// Given a Vec<Statement> and an EnvSet, compile it into a Recipe
+/// Given a parameter list, a Vec<Statement> and an EnvSet, compile it into a Recipe
+///
+/// `span` is where the recipe appears in its moldfile's `source`, and `file_vars` are the
+/// variables that moldfile has defined so far. An `extend` recipe's `super` statement decides
+/// where the commands it inherits go.
 pub fn compile_recipe(
+    params: Vec<super::Param>,
     body: Vec<Statement>,
+    mut modifier: super::Modifier,
+    span: Span,
+    source: &Source,
+    file_vars: &super::VarMap,
     mold: &mut super::Mold,
 ) -> Result<super::Recipe, Error> {
-    use Statement::*;
+    use StatementKind::*;
+
+    for (idx, param) in params.iter().enumerate() {
+        if params[..idx].iter().any(|x| x.name == param.name) {
+            let message = format!("Duplicate parameter specified: {}", param.name);
+            return Err(source.error(span, message));
+        }
+    }
 
     let mut help = None;
     let mut dir = None;
+    let mut shell = None;
+    let mut hooks = super::Hooks::default();
     let mut commands = vec![];
+    let mut ignore_errors = false;
+    let mut allowed_exit = vec![];
     let mut requires = super::TargetSet::new();
+    let mut vars = super::VarMap::new();
+    let mut defaults = super::NameSet::new();
+    let mut has_super = false;
+
+    let mut body: VecDeque<_> = body.into();
+    let no_defaults = super::NameSet::new();
 
-    let body = flatten(body, &mold.envs)?;
+    while let Some(Statement { kind, span }) = body.pop_front() {
+        match kind {
+            IfBlock(cases) => {
+                check_param_conditions(&cases, &params, &vars, source)?;
+                let case = select(cases, source, &mold.envs, |names| {
+                    visible_vars(
+                        mold,
+                        &[(file_vars, &no_defaults), (&vars, &defaults)],
+                        names,
+                    )
+                })?;
+                unroll(&mut body, case);
+            }
 
-    for stmt in body {
-        match stmt {
             Help(s) => {
                 help = Some(s);
             }
@@ -320,68 +1083,651 @@ pub fn compile_recipe(
                 dir = Some(s);
             }
 
-            Run(cmd) => {
-                commands.push(cmd);
+            Shell(cmd) => {
+                shell = Some(cmd);
+            }
+
+            Run(cmd, is_tolerant) => {
+                commands.push(super::Command::Run(super::Line::new(cmd), is_tolerant));
+            }
+
+            IgnoreErrors => {
+                ignore_errors = true;
+            }
+
+            AllowedExit(codes) => {
+                allowed_exit = codes;
+            }
+
+            Hook(hook, cmd) => {
+                hooks.push(hook, cmd);
+            }
+
+            Script(runtime, body) => {
+                commands.push(super::Command::Script(runtime, body));
+            }
+
+            File(path, runtime) => {
+                commands.push(super::Command::File(path, runtime));
             }
 
             Require(recipe) => {
                 requires.insert(recipe);
             }
 
-            _ => unreachable!(),
+            Super => match modifier {
+                super::Modifier::Extend(0) if !has_super => {
+                    has_super = true;
+                    modifier = super::Modifier::Extend(commands.len());
+                }
+                super::Modifier::Extend(_) => {
+                    return Err(source.error(span, "Duplicate super specified".into()))
+                }
+                _ => {
+                    return Err(source.error(
+                        span,
+                        "super statements are only allowed in an extend recipe".into(),
+                    ))
+                }
+            },
+
+            Var(name, value) => {
+                if mold.use_vars {
+                    defaults.shift_remove(&name);
+                    vars.insert(name, value);
+                }
+            }
+
+            // whether a default applies depends on the global variables, which
+            // aren't known until every moldfile has been loaded
+            Default(name, value) => {
+                if mold.use_vars && !vars.contains_key(&name) {
+                    defaults.insert(name.clone());
+                    vars.insert(name, value);
+                }
+            }
+
+            kind => {
+                return Err(source.error(
+                    span,
+                    format!("{} statements aren't allowed in a recipe", kind.keyword()),
+                ))
+            }
         }
     }
 
     Ok(super::Recipe {
         help,
+        params,
         commands,
         dir,
+        shell,
         requires,
+        vars,
+        defaults,
+        hooks,
+        ignore_errors,
+        allowed_exit,
+        private: false,
+        modifier,
+        merged: false,
+        origin: (source.path.to_path_buf(), span),
     })
 }
 
-/// Given a Vec<Statement> and an EnvSet, remove all falsy If statements
+/// Make sure that none of a recipe's conditions depend on its parameters
+///
+/// Conditions are resolved when the recipe is compiled, long before it's given any arguments, so
+/// they would always see its parameters as undefined. `vars` are the recipe's own variables,
+/// which can pass a parameter along into a condition.
+fn check_param_conditions(
+    cases: &[Statement],
+    params: &[super::Param],
+    vars: &super::VarMap,
+    source: &Source,
+) -> Result<(), Error> {
+    for case in cases {
+        if let StatementKind::If(expr, _) = &case.kind {
+            let mut names = super::NameSet::new();
+            expr.vars(&mut names);
+
+            for (name, var) in vars.iter().rev() {
+                if let super::Var::Value(text) | super::Var::Command(text) = var {
+                    if names.contains(name) {
+                        names.extend(var_refs(text));
+                    }
+                }
+            }
+
+            if let Some(param) = params.iter().find(|x| names.contains(&x.name)) {
+                let message = format!(
+                    "Conditions can't refer to parameter {}, since they're resolved before \
+                     arguments are bound",
+                    param.name
+                );
+                return Err(source.error(case.span, message));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the moldfiles that an `include` refers to, relative to the moldfile that includes them
+///
+/// Patterns can be globs, and directories are searched for a moldfile.
+fn find_includes(source: &Source, span: Span, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let full = source.dir().join(pattern);
+    let paths = glob::glob(&full.to_string_lossy())
+        .map_err(|err| source.error(span, format!("Invalid include pattern: {}", err)))?;
+
+    let mut found = vec![];
+    for path in paths {
+        let path = path.map_err(|err| source.error(span, format!("Couldn't include: {}", err)))?;
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        if path.is_dir() {
+            found.push(path.join("moldfile"));
+        } else {
+            found.push(path);
+        }
+    }
+
+    if found.is_empty() {
+        let message = format!("Couldn't find any moldfiles to include at {}", pattern);
+        return Err(source.error(span, message));
+    }
+
+    Ok(found)
+}
+
+/// Given the cases of an IfBlock, return the body of the first case that applies
 ///
 /// Much like Statement::from above, this will not behave correctly on arbitrary Statements. It
 /// operates under the assumption that it's receiving Statements that adhere to the Pest grammar.
-/// In particular, it's expected that the only top-level conditional structure that needs to be
-/// flattened is an IfBlock, which will only contain a sequence of If statements followed by an
-/// optional Else statement. If these assumptions are ever violated, this function will simply not
-/// work as expected.
-pub fn flatten(body: Vec<Statement>, envs: &super::EnvSet) -> Result<Vec<Statement>, Error> {
-    let mut ret = vec![];
-
-    for stmt in body {
-        match stmt {
-            // IfBlock is the only conditional structure we flatten, and it should only ever contain a
-            // series of If statements followed by an optional Else. Anything else will break this.
-            Statement::IfBlock(cases) => {
+/// In particular, it's expected that an IfBlock will only contain a sequence of If statements
+/// followed by an optional Else statement. If these assumptions are ever violated, this function
+/// will simply not work as expected.
+///
+/// `vars` is given the names of the variables the conditions refer to, and is only called if there
+/// are any, since evaluating computed variables can be expensive.
+pub fn select<F>(
+    cases: Vec<Statement>,
+    source: &Source,
+    envs: &super::EnvSet,
+    vars: F,
+) -> Result<Vec<Statement>, Error>
+where
+    F: FnOnce(&super::NameSet) -> Result<super::ValueMap, Error>,
+{
+    let mut names = super::NameSet::new();
+    for case in &cases {
+        if let StatementKind::If(expr, _) = &case.kind {
+            expr.vars(&mut names);
+        }
+    }
+
+    let context = Context {
+        envs,
+        vars: if names.is_empty() {
+            super::ValueMap::new()
+        } else {
+            vars(&names)?
+        },
+        dir: source.dir(),
+    };
+
+    for Statement { kind, span } in cases {
+        match kind {
+            // If should check if its condition applies, and if so, return its contents. This gives
+            // us the if..elif behavior.
+            StatementKind::If(expr, body) => {
+                let applies = expr
+                    .apply(&context)
+                    .map_err(|err| source.error(span, err.to_string()))?;
+                if applies {
+                    return Ok(body);
+                }
+            }
+            // Else has no condition to check, so it unconditionally applies. Because of the grammar
+            // constraints, this should only ever appear as the last case.
+            StatementKind::Else(body) => return Ok(body),
+            // Nothing else should ever appear in an IfBlock.
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Call a function on every statement in a body, including the bodies of blocks
+pub fn visit<'a, F>(stmts: &'a [Statement], func: &mut F)
+where
+    F: FnMut(&'a Statement),
+{
+    for stmt in stmts {
+        func(stmt);
+
+        match &stmt.kind {
+            StatementKind::IfBlock(body)
+            | StatementKind::If(_, body)
+            | StatementKind::Else(body)
+            | StatementKind::Group(_, body)
+            | StatementKind::Recipe(_, _, body, ..) => visit(body, func),
+            _ => {}
+        }
+    }
+}
+
+/// Find where a variable is defined in a body, not including the bodies of recipes
+///
+/// When it's defined more than once, the last definition is found, since that's the one that takes
+/// effect.
+pub fn find_var(stmts: &[Statement], name: &str) -> Option<Span> {
+    let mut span = None;
+
+    for stmt in stmts {
+        match &stmt.kind {
+            StatementKind::Var(var_name, _) | StatementKind::Default(var_name, _)
+                if var_name == name =>
+            {
+                span = Some(stmt.span);
+            }
+            StatementKind::IfBlock(cases) => {
                 for case in cases {
-                    match case {
-                        // If should check if its condition applies, and if so, push its contents and then
-                        // break the loop. This gives us the if..elif behavior.
-                        Statement::If(expr, body) => {
-                            if expr.apply(envs) {
-                                ret.extend(flatten(body, envs)?);
-                                break;
-                            }
-                        }
-                        // Else has no condition to check, so it unconditionally applies and breaks. Because of
-                        // the grammar constraints, this should only ever appear as the last case.
-                        Statement::Else(body) => {
-                            ret.extend(flatten(body, envs)?);
-                            break;
-                        }
-                        // Nothing else should ever appear in an IfBlock.
-                        _ => unreachable!(),
+                    if let StatementKind::If(_, body) | StatementKind::Else(body) = &case.kind {
+                        span = find_var(body, name).or(span);
                     }
                 }
             }
+            _ => {}
+        }
+    }
+
+    span
+}
+
+/// Collect every distinct condition in an Expr that isn't made of other conditions, like env
+/// atoms and comparisons
+pub fn collect_atoms<'a>(expr: &'a Expr, atoms: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::And(x, y) | Expr::Or(x, y) => {
+            collect_atoms(x, atoms);
+            collect_atoms(y, atoms);
+        }
+        Expr::Not(x) | Expr::Group(x) => collect_atoms(x, atoms),
+        Expr::Wild => {}
+        _ => {
+            if !atoms.contains(&expr) {
+                atoms.push(expr);
+            }
+        }
+    }
+}
+
+/// Push a selected body onto the front of a statement queue, preserving its order
+fn unroll(queue: &mut VecDeque<Statement>, body: Vec<Statement>) {
+    for stmt in body.into_iter().rev() {
+        queue.push_front(stmt);
+    }
+}
+
+/// Given a Statement from inside a `group` block, prefix the name it defines with the group's name
+///
+/// Only recipes, aliases, groups and conditions are allowed in a group.
+fn ungroup(group: &str, stmt: Statement, source: &Source) -> Result<Statement, Error> {
+    use StatementKind::*;
+    let prefixed = |name| format!("{}/{}", group, name);
+    let in_group = |body: Vec<Statement>| {
+        body.into_iter()
+            .map(|stmt| ungroup(group, stmt, source))
+            .collect::<Result<Vec<_>, _>>()
+    };
+
+    let kind = match stmt.kind {
+        Recipe(name, params, body, private, modifier) => {
+            Recipe(prefixed(name), params, body, private, modifier)
+        }
+        Alias(name, recipe) => Alias(prefixed(name), recipe),
+        Group(name, body) => Group(prefixed(name), body),
+        IfBlock(cases) => IfBlock(in_group(cases)?),
+        If(expr, body) => If(expr, in_group(body)?),
+        Else(body) => Else(in_group(body)?),
+        kind => {
+            return Err(source.error(
+                stmt.span,
+                format!("{} statements aren't allowed in a group", kind.keyword()),
+            ))
+        }
+    };
+
+    Ok(Statement {
+        kind,
+        span: stmt.span,
+    })
+}
+
+/// Resolve a name that's used by the recipe or alias named `owner`
+///
+/// Recipes in a group (eg, `docker/build`) can refer to the other recipes in that group and its
+/// parent groups without a prefix, which take precedence over recipes at the top level.
+pub fn relative(owner: &str, name: &str, defined: &super::NameSet) -> String {
+    let mut group = owner;
+
+    while let Some(idx) = group.rfind('/') {
+        group = &group[..idx];
+        let candidate = format!("{}/{}", group, name);
+        if defined.contains(&candidate) {
+            return candidate;
+        }
+    }
+
+    name.to_string()
+}
+
+/// Evaluate the variables named in `names` as they're visible at some point in a moldfile
+///
+/// `locals` are variables that have been compiled but haven't been merged into the Mold yet, in
+/// the order they apply. Names in a local's NameSet are defaults, which are skipped if the
+/// variable is already defined. Only the named variables and the ones they refer to are
+/// evaluated, so unrelated computed variables are never executed.
+fn visible_vars(
+    mold: &super::Mold,
+    locals: &[(&super::VarMap, &super::NameSet)],
+    names: &super::NameSet,
+) -> Result<super::ValueMap, Error> {
+    // variables can only refer to the ones defined before them, so walking backwards finds
+    // everything the named variables depend on
+    let all: Vec<_> = mold
+        .vars
+        .iter()
+        .chain(locals.iter().flat_map(|(vars, _)| vars.iter()))
+        .collect();
+
+    let mut needed = names.clone();
+    for (name, var) in all.iter().rev() {
+        if needed.contains(*name) {
+            match var {
+                super::Var::Value(text) | super::Var::Command(text) => {
+                    needed.extend(var_refs(text))
+                }
+                super::Var::Unset => {}
+            }
+        }
+    }
+
+    let mut values = super::ValueMap::new();
+    for (name, var) in mold.vars.iter().filter(|(name, _)| needed.contains(*name)) {
+        let value = mold.evaluate_for_condition(name, var, &values, &mold.var_origin(name))?;
+        values.insert(name.clone(), value);
+    }
+
+    for (vars, defaults) in locals {
+        for (name, var) in vars.iter().filter(|(name, _)| needed.contains(*name)) {
+            let defined = match values.get(name) {
+                Some(value) => value.is_some(),
+                None => std::env::var(name).is_ok(),
+            };
+
+            if defaults.contains(name) && defined {
+                continue;
+            }
+
+            let value = mold.evaluate_for_condition(name, var, &values, "the current moldfile")?;
+            values.insert(name.clone(), value);
+        }
+    }
+
+    Ok(values)
+}
+
+/// Find the names of every variable that expanding a string would look up
+///
+/// This follows the same rules as shellexpand, where `$$` is a literal `$` and `${NAME:-x}`
+/// refers to NAME. Splats like `$@NAME` refer to NAME as well.
+pub fn var_refs(text: &str) -> Vec<String> {
+    scan_var_refs(text)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Find the names of the variables that expanding a string needs to be defined
+///
+/// This is the same as `var_refs`, except references with a default like `${NAME:-x}` or
+/// `${NAME-x}` are skipped.
+pub fn required_var_refs(text: &str) -> Vec<String> {
+    scan_var_refs(text)
+        .into_iter()
+        .filter(|(_, default)| !default)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Find every variable reference in a string, and whether it has a default
+fn scan_var_refs(text: &str) -> Vec<(String, bool)> {
+    let mut names = vec![];
+    let mut rest = text;
+
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+
+        rest = rest.strip_prefix('@').unwrap_or(rest);
+        if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').unwrap_or(after.len());
+            let inner = &after[..end];
+            let split = inner
+                .find(|x: char| !(x.is_ascii_alphanumeric() || x == '_'))
+                .unwrap_or(inner.len());
+            let default = inner[split..].starts_with(":-") || inner[split..].starts_with('-');
+            names.push((inner[..split].to_string(), default));
+            rest = &after[end..];
+        } else {
+            let end = rest
+                .find(|x: char| !(x.is_ascii_alphanumeric() || x == '_'))
+                .unwrap_or(rest.len());
+            if end > 0 {
+                names.push((rest[..end].to_string(), false));
+            }
+            rest = &rest[end..];
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(code: &str) -> Source<'_> {
+        Source {
+            code,
+            path: Path::new("moldfile"),
+        }
+    }
+
+    /// Parse some code into the kinds of its top-level statements
+    fn kinds(code: &str) -> Vec<StatementKind> {
+        parse(&source(code))
+            .unwrap()
+            .into_iter()
+            .map(|x| x.kind)
+            .collect()
+    }
+
+    /// Compile some code with the given environments active
+    fn compile_with(code: &str, envs: &[&str]) -> Result<super::super::Moldfile, Error> {
+        let path = std::env::temp_dir().join("moldfile");
+        let envs = envs.iter().map(|x| x.to_string()).collect();
+        let mut mold = super::super::Mold::new(&path, envs, false, true, false)?;
+        let source = Source { code, path: &path };
+        compile(&source, &mut mold, super::super::VarMap::new())
+    }
+
+    /// Compile some code and return the commands of the recipe named r
+    fn commands(code: &str, envs: &[&str]) -> Vec<String> {
+        let moldfile = compile_with(code, envs).unwrap();
+        moldfile.recipes["r"]
+            .commands
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    /// Parse a single run statement and return its string
+    fn string(code: &str) -> String {
+        match kinds(&format!("$ {}", code)).pop() {
+            Some(StatementKind::Run(text, false)) => text,
+            kind => panic!("expected a run statement, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn strings_process_escapes() {
+        assert_eq!(string(r#""a\tb\n\"c\" \\""#), "a\tb\n\"c\" \\");
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes_and_dollars() {
+        assert_eq!(string(r#"r"a\n $HOME""#), "a\\n $$HOME");
+        assert_eq!(string("r\"\"\"\n  a\\n \"x\"\n  \"\"\""), "a\\n \"x\"");
+
+        // strings that are never expanded don't need their dollars escaped
+        let help = kinds(r#"help r"$HOME""#).pop();
+        assert_eq!(help, Some(StatementKind::Help("$HOME".into())));
+    }
+
+    #[test]
+    fn long_strings_are_dedented() {
+        let code = "\"\"\"\n    first\n      second\n\n    third\n    \"\"\"";
+        assert_eq!(string(code), "first\n  second\n\nthird");
+        assert_eq!(
+            string("\"\"\"one \"quoted\" line\"\"\""),
+            "one \"quoted\" line"
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_are_located() {
+        let err = parse(&source("version \"0.7\"\nhelp \"a\\qb\"\n"))
+            .err()
+            .unwrap();
+        let err = err.to_string();
+        assert!(err.contains("invalid escape sequence \\q"), "{}", err);
+        assert!(err.contains("moldfile:2:8"), "{}", err);
+    }
+
+    #[test]
+    fn syntax_errors_name_what_was_expected() {
+        let err = parse(&source("version \"0.7\"\nrecipe r {\n  var = \"x\"\n}\n")).err();
+        let err = err.unwrap().to_string();
+        assert!(err.contains("moldfile:3:7"), "{}", err);
+        assert!(err.contains("expected name"), "{}", err);
+
+        let err = parse(&source("version \"0.7\"\nbogus\n"))
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(err.matches("var").count(), 1, "{}", err);
+    }
+
+    #[test]
+    fn compile_errors_point_at_their_statement() {
+        let err = |code| compile_with(code, &[]).err().unwrap().to_string();
 
-            // All non-IfBlock statemnts are pushed through transparently.
-            x => ret.push(x),
+        let e = err("version \"0.7\"\nversion \"0.8\"\n");
+        assert!(e.contains("Duplicate version specified"), "{}", e);
+        assert!(e.contains("moldfile:2:1"), "{}", e);
+
+        let e = err("version \"0.7\"\n$ \"echo\"\n");
+        assert!(
+            e.contains("run statements are only allowed in a recipe"),
+            "{}",
+            e
+        );
+
+        let e = err("recipe r {}\n");
+        assert!(e.contains("File version must be specified"), "{}", e);
+        assert!(e.contains("moldfile:1:1"), "{}", e);
+
+        // only the first line of a statement is shown
+        let e = err("version \"0.7\"\nrecipe r(x, x) {\n  $ \"echo hidden\"\n}\n");
+        assert!(e.contains("Duplicate parameter specified: x"), "{}", e);
+        assert!(!e.contains("hidden"), "{}", e);
+    }
+
+    #[test]
+    fn finds_variable_references() {
+        let text = "$A ${B} $$C ${D:-x} ${E-y} $@F $@{G} $1 $ x$H_2.";
+        assert_eq!(var_refs(text), ["A", "B", "D", "E", "F", "G", "1", "H_2"]);
+        assert_eq!(required_var_refs(text), ["A", "B", "F", "G", "1", "H_2"]);
+    }
+
+    #[test]
+    fn conditions_compare_variables() {
+        let code = |cond: &str| {
+            format!(
+                "version \"0.7\"\nvar OS = \"linux\"\nvar EMPTY = \"\"\n\
+                 recipe r {{\n  if {} {{\n    $ \"yes\"\n  }} else {{\n    $ \"no\"\n  }}\n}}\n",
+                cond
+            )
+        };
+        let cases = [
+            ("$OS == \"linux\"", "yes"),
+            ("$OS != \"linux\"", "no"),
+            ("$OS =~ \"^li\"", "yes"),
+            ("defined(EMPTY)", "yes"),
+            ("defined(MOLD_TEST_UNDEFINED)", "no"),
+            ("$MOLD_TEST_UNDEFINED == \"\"", "yes"),
+            (
+                "~($OS == \"mac\") + ($OS == \"linux\" | $OS == \"mac\")",
+                "yes",
+            ),
+        ];
+        for (cond, expected) in &cases {
+            assert_eq!(commands(&code(cond), &[]), [*expected], "{}", cond);
+        }
+
+        let err = compile_with(&code("$OS =~ \"(\""), &[]).err().unwrap();
+        assert!(err.to_string().contains("Invalid regex"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn predicates_check_the_world_outside() {
+        let code = |cond: &str| {
+            format!(
+                "version \"0.7\"\nvar DIR = \".\"\nif {} {{\n  recipe r {{ $ \"yes\" }}\n}} \
+                 else {{\n  recipe r {{ $ \"no\" }}\n}}\n",
+                cond
+            )
+        };
+        let cases = [
+            ("is_dir(\".\")", "yes"),
+            ("is_dir(\"$DIR\")", "yes"),
+            ("exists(\"mold-test-missing\")", "no"),
+            ("has_cmd(\"sh\")", "yes"),
+            ("has_cmd(\"mold-test-missing\")", "no"),
+            ("env(\"PATH\")", "yes"),
+            ("env(\"MOLD_TEST_UNDEFINED\")", "no"),
+            ("linux | has_cmd(\"sh\")", "yes"),
+        ];
+        for (cond, expected) in &cases {
+            assert_eq!(commands(&code(cond), &[]), [*expected], "{}", cond);
         }
+        assert_eq!(commands(&code("linux + ~mac"), &["linux"]), ["yes"]);
     }
 
-    Ok(ret)
+    #[test]
+    fn conditions_cant_use_parameters() {
+        let code = "version \"0.7\"\nrecipe r(x) {\n  var Y = \"$x\"\n  if $Y == \"a\" {\n    $ \"a\"\n  }\n}\n";
+        let err = compile_with(code, &[]).err().unwrap().to_string();
+        assert!(err.contains("can't refer to parameter x"), "{}", err);
+        assert!(err.contains(":4:3"), "{}", err);
+    }
 }
@@ -1,5 +1,8 @@
 mod cargo;
+pub mod check;
+pub mod fmt;
 pub mod lang;
+pub mod lsp;
 pub mod remote;
 pub mod util;
 
@@ -11,6 +14,7 @@ use indexmap::IndexSet;
 use remote::Remote;
 use semver::Version;
 use semver::VersionReq;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::fs;
 use std::io::prelude::*;
@@ -18,13 +22,21 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::string::ToString;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 // sorted by insertion order
 pub type IncludeVec = Vec<Include>;
-pub type TargetSet = IndexSet<String>;
+pub type TargetSet = IndexSet<Target>;
 pub type EnvSet = IndexSet<String>;
-pub type VarMap = IndexMap<String, String>; // TODO maybe down the line this should allow nulls to `unset` a variable
+pub type NameSet = IndexSet<String>;
+pub type VarMap = IndexMap<String, Var>;
+pub type ValueMap = IndexMap<String, Option<String>>; // None means the variable was explicitly unset
 pub type SourceMap = IndexMap<String, PathBuf>;
+pub type RuntimeMap = IndexMap<String, Runtime>;
+pub type AliasMap = IndexMap<String, String>;
+type ComputedMap = IndexMap<(Vec<String>, Vec<(String, String)>), String>; // keyed by args and env
 
 // sorted alphabetically
 pub type RecipeMap = BTreeMap<String, Recipe>;
@@ -40,9 +52,38 @@ pub struct Mold {
     /// A map of recipe sources
     pub sources: SourceMap,
 
+    /// A map of the directories that recipes from local includes resolve their `dir` against,
+    /// rather than $MOLD_ROOT
+    pub dirs: SourceMap,
+
+    /// A map of alternate names to the recipes they refer to
+    pub aliases: AliasMap,
+
+    /// A map of alias sources
+    pub alias_sources: SourceMap,
+
+    /// Every moldfile that has been loaded and the prefix it was loaded with, in the order they
+    /// were loaded
+    pub files: Vec<(PathBuf, String)>,
+
     /// A map of environment variables
     pub vars: VarMap,
 
+    /// A map of variable sources
+    pub var_sources: SourceMap,
+
+    /// A map of the variables that recipes from imports with a `with` block see on top of the
+    /// global ones
+    ///
+    /// This includes the imported moldfile's own variables, which aren't made global.
+    pub import_vars: IndexMap<String, VarMap>,
+
+    /// A cache of computed variable values, keyed by their expanded command and environment
+    computed: RefCell<ComputedMap>,
+
+    /// A map of script runtimes
+    pub runtimes: RuntimeMap,
+
     /// List of Remotes that have been imported
     pub remotes: Vec<Remote>,
 
@@ -57,11 +98,49 @@ pub struct Mold {
     /// This is overridden by a recipe's `dir`
     pub work_dir: Option<String>,
 
+    /// Recipes to run when no targets are given
+    pub default_targets: Vec<Target>,
+
     /// Use external git binary rather than libgit2
     pub use_git: bool,
 
+    /// Clone remotes that haven't been cloned yet when they're imported
+    ///
+    /// When this is false, importing a remote that hasn't been cloned is an error
+    pub fetch: bool,
+
     /// Skip variables when compiling moldfiles
     pub use_vars: bool,
+
+    /// Treat expansion of undefined variables as an error
+    pub strict: bool,
+
+    /// Never run commands or write files, for describing what would happen
+    ///
+    /// Computed variables keep their unexpanded `$(...)` text and scripts are never written.
+    pub dry: bool,
+
+    /// Still execute the computed variables that conditions depend on in dry mode
+    ///
+    /// Conditions decide which recipes and commands exist, so without this a dry run can pick
+    /// different branches than a real run would.
+    pub compute_conditions: bool,
+
+    /// Set once mold is interrupted after catch_interrupts has been called
+    interrupted: Arc<AtomicBool>,
+
+    /// Canonical paths of the moldfiles that are in the middle of being loaded, innermost last
+    loading: Vec<PathBuf>,
+}
+
+/// The recipes and aliases that were loaded before an import, so that whatever its selection drops
+/// can be put back
+struct Snapshot {
+    recipes: RecipeMap,
+    sources: SourceMap,
+    dirs: SourceMap,
+    import_vars: IndexMap<String, VarMap>,
+    aliases: NameSet,
 }
 
 /// An external module included for reuse
@@ -71,6 +150,83 @@ pub struct Include {
 
     /// Prefix to prepend
     pub prefix: String,
+
+    /// Which of its recipes to use
+    pub selection: Selection,
+
+    /// Variables to pass into it with `with`, which are only visible to it
+    ///
+    /// Even an empty `with {}` keeps the module's own variables apart from the importer's.
+    pub vars: Option<VarMap>,
+}
+
+/// Which recipes to use from an imported moldfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    All,
+
+    /// Only these recipes, and the recipes they require
+    Only(NameSet),
+
+    /// Every recipe except these, unless another recipe requires them
+    Except(NameSet),
+}
+
+impl Selection {
+    /// Split the recipes that an import defined into the ones it picks and the ones that only
+    /// come along because a picked recipe requires them
+    ///
+    /// `defined` is every recipe the import defined, including the ones from the moldfiles it
+    /// imports or includes itself, and names in the selection are relative to its `prefix`.
+    fn select(
+        &self,
+        prefix: &str,
+        defined: &NameSet,
+        recipes: &RecipeMap,
+        aliases: &AliasMap,
+    ) -> Result<(NameSet, NameSet), Error> {
+        let names = match self {
+            Selection::All => return Ok((defined.clone(), NameSet::new())),
+            Selection::Only(names) | Selection::Except(names) => names,
+        };
+
+        let resolve = |name: &str| aliases.get(name).map_or(name, String::as_str).to_string();
+        let mut named = NameSet::new();
+        for name in names {
+            let key = resolve(&format!("{}{}", prefix, name));
+            if !defined.contains(&key) {
+                return Err(failure::format_err!(
+                    "there's no recipe named {}",
+                    name.red()
+                ));
+            }
+            named.insert(key);
+        }
+
+        let picked: NameSet = match self {
+            Selection::Only(_) => named,
+            _ => defined
+                .iter()
+                .filter(|x| !named.contains(*x))
+                .cloned()
+                .collect(),
+        };
+
+        // anything a picked recipe requires has to come along with it
+        let mut required = NameSet::new();
+        let mut queue: Vec<_> = picked.iter().cloned().collect();
+        while let Some(name) = queue.pop() {
+            for dep in recipes.get(&name).iter().flat_map(|x| &x.requires) {
+                let dep = resolve(&dep.name);
+                if defined.contains(&dep) && !picked.contains(&dep) && required.insert(dep.clone())
+                {
+                    queue.push(dep);
+                }
+            }
+        }
+
+        Ok((picked, required))
+    }
 }
 
 /// A single task to execute
@@ -79,14 +235,384 @@ pub struct Recipe {
     /// A short description of the recipe
     pub help: Option<String>,
 
+    /// A list of parameters that get bound as variables
+    pub params: Vec<Param>,
+
     /// Working directory relative to $MOLD_ROOT
     pub dir: Option<String>,
 
-    /// The command to execute
-    pub commands: Vec<String>,
+    /// A shell from the recipe's own `shell` statement, which takes precedence over its
+    /// moldfile's
+    ///
+    /// Each of the recipe's Lines is given the shell that applies to it when it's compiled.
+    pub shell: Option<String>,
+
+    /// The commands to execute
+    pub commands: Vec<Command>,
 
     /// A list of prerequisite recipes
     pub requires: TargetSet,
+
+    /// A list of variables that only apply to this recipe
+    pub vars: VarMap,
+
+    /// Names of variables in `vars` that only apply if they aren't already defined
+    pub defaults: NameSet,
+
+    /// Commands to run around this recipe's own commands
+    pub hooks: Hooks,
+
+    /// Keep going when any of this recipe's commands fail
+    pub ignore_errors: bool,
+
+    /// Non-zero exit statuses that don't count as a failure
+    pub allowed_exit: Vec<i32>,
+
+    /// Hide this recipe from help and the command line so it can only be required
+    pub private: bool,
+
+    /// How this recipe combines with another recipe of the same name
+    pub modifier: Modifier,
+
+    /// Whether this recipe has been combined with another one, so an override or extension has
+    /// found the recipe it's based on
+    pub merged: bool,
+
+    /// The moldfile this recipe was defined in and where, for pointing errors at it
+    pub origin: (PathBuf, lang::Span),
+}
+
+/// Commands that run around a recipe
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// Commands to run before the recipe's commands
+    pub before: Vec<Line>,
+
+    /// Commands to run after the recipe's commands, if they all succeed
+    pub after: Vec<Line>,
+
+    /// Commands that always run last, even if the recipe fails
+    ///
+    /// They can see the exit status of the recipe as $MOLD_STATUS.
+    pub finally: Vec<Line>,
+}
+
+/// Which part of a recipe a hook runs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Before,
+    After,
+    Finally,
+}
+
+impl Hooks {
+    /// Add a command to the given hook
+    pub fn push(&mut self, hook: Hook, command: String) {
+        let command = Line::new(command);
+        match hook {
+            Hook::Before => self.before.push(command),
+            Hook::After => self.after.push(command),
+            Hook::Finally => self.finally.push(command),
+        }
+    }
+
+    /// Surround another set of hooks with these ones
+    ///
+    /// Outer `before` commands run first, and outer `after` and `finally` commands run last.
+    pub fn wrap(mut self, mut inner: Hooks) -> Hooks {
+        self.before.append(&mut inner.before);
+        inner.after.append(&mut self.after);
+        inner.finally.append(&mut self.finally);
+        Hooks {
+            before: self.before,
+            after: inner.after,
+            finally: inner.finally,
+        }
+    }
+}
+
+/// How a recipe combines with another recipe of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    /// Can't share its name with another recipe
+    Plain,
+
+    /// Replaces the other recipe
+    Override,
+
+    /// Adds to the other recipe, whose commands are inserted at this index of its own
+    Extend(usize),
+}
+
+impl Modifier {
+    /// Whether a recipe with this modifier is the one that's kept when combined with `other`
+    fn is_base(self, other: Modifier) -> bool {
+        match (self, other) {
+            (Modifier::Extend(_), _) => false,
+            (_, Modifier::Extend(_)) => true,
+            (Modifier::Override, _) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A variable definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Var {
+    /// A string that gets expanded when it's used
+    Value(String),
+
+    /// A command whose output becomes the value the first time it's needed
+    Command(String),
+
+    /// Removes the variable, including from the environment mold was run in
+    Unset,
+}
+
+/// A named recipe parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Param {
+    /// Name of the variable to bind
+    pub name: String,
+
+    /// Value to use when no argument is passed, making the parameter optional
+    pub default: Option<String>,
+}
+
+/// A reference to a recipe and the arguments to invoke it with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    /// Name of the recipe
+    pub name: String,
+
+    /// Arguments to bind to the recipe's parameters
+    pub args: Vec<Arg>,
+}
+
+/// A single argument passed to a recipe
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Arg {
+    Positional(String),
+    Named(String, String),
+}
+
+impl Target {
+    /// Create a Target with no arguments
+    pub fn new(name: &str) -> Self {
+        Target {
+            name: name.into(),
+            args: vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if !self.args.is_empty() {
+            let args: Vec<_> = self
+                .args
+                .iter()
+                .map(|arg| match arg {
+                    Arg::Positional(value) => shell_words::quote(value).to_string(),
+                    Arg::Named(name, value) => format!("{}={}", name, shell_words::quote(value)),
+                })
+                .collect();
+            write!(f, "({})", args.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Recipe {
+    /// Combine this recipe with another one of the same name, according to their modifiers
+    pub fn merge(self, other: Recipe, name: &str) -> Result<Recipe, String> {
+        match (self.modifier, other.modifier) {
+            (Modifier::Plain, Modifier::Plain) => Err(format!(
+                "Recipe {} is already defined; use override or extend to change it",
+                name.red()
+            )),
+            (Modifier::Override, Modifier::Override) => Err(format!(
+                "Recipe {} is overridden more than once",
+                name.red()
+            )),
+            (Modifier::Extend(_), Modifier::Extend(_)) => {
+                Err(format!("Recipe {} is extended more than once", name.red()))
+            }
+            (this, _) if this.is_base(other.modifier) => Ok(Recipe {
+                merged: true,
+                ..self.extend(other)
+            }),
+            _ => Ok(Recipe {
+                merged: true,
+                ..other.extend(self)
+            }),
+        }
+    }
+
+    /// Apply an extension to this recipe, if it is one
+    ///
+    /// The extension's requires and allowed exit statuses are added to this recipe's, its hooks
+    /// surround this recipe's, and its help, dir and variables take precedence. Every line keeps
+    /// the shell it was compiled with.
+    fn extend(mut self, ext: Recipe) -> Recipe {
+        let at = match ext.modifier {
+            Modifier::Extend(at) => at,
+            _ => return self,
+        };
+
+        let mut commands = ext.commands;
+        let after = commands.split_off(at);
+        commands.append(&mut self.commands);
+        commands.extend(after);
+        self.commands = commands;
+
+        for param in ext.params {
+            if !self.params.iter().any(|x| x.name == param.name) {
+                self.params.push(param);
+            }
+        }
+
+        for (name, var) in ext.vars {
+            if !ext.defaults.contains(&name) {
+                self.defaults.shift_remove(&name);
+                self.vars.insert(name, var);
+            } else if !self.vars.contains_key(&name) {
+                self.defaults.insert(name.clone());
+                self.vars.insert(name, var);
+            }
+        }
+
+        self.help = ext.help.or(self.help);
+        self.dir = ext.dir.or(self.dir);
+        self.requires.extend(ext.requires);
+        self.hooks = ext.hooks.wrap(std::mem::take(&mut self.hooks));
+        self.ignore_errors |= ext.ignore_errors;
+        for status in ext.allowed_exit {
+            if !self.allowed_exit.contains(&status) {
+                self.allowed_exit.push(status);
+            }
+        }
+        self.private |= ext.private;
+        self
+    }
+
+    /// Every command line the recipe executes, including its hooks
+    pub fn lines(&self) -> impl Iterator<Item = &Line> {
+        let hooks = &self.hooks;
+        self.commands
+            .iter()
+            .filter_map(|x| match x {
+                Command::Run(line, _) => Some(line),
+                _ => None,
+            })
+            .chain(&hooks.before)
+            .chain(&hooks.after)
+            .chain(&hooks.finally)
+    }
+
+    /// Every command line the recipe executes, including its hooks, for changing them
+    fn lines_mut(&mut self) -> impl Iterator<Item = &mut Line> {
+        let hooks = &mut self.hooks;
+        self.commands
+            .iter_mut()
+            .filter_map(|x| match x {
+                Command::Run(line, _) => Some(line),
+                _ => None,
+            })
+            .chain(&mut hooks.before)
+            .chain(&mut hooks.after)
+            .chain(&mut hooks.finally)
+    }
+
+    /// Render the recipe's name along with its parameter list
+    fn signature(&self, name: &str) -> String {
+        if self.params.is_empty() {
+            return name.into();
+        }
+
+        let params: Vec<_> = self
+            .params
+            .iter()
+            .map(|param| match &param.default {
+                Some(default) => format!("{}={}", param.name, shell_words::quote(default)),
+                None => param.name.clone(),
+            })
+            .collect();
+        format!("{}({})", name, params.join(", "))
+    }
+}
+
+/// A command line and the shell it's passed to as a single argument, if there is one
+///
+/// The shell comes from the recipe or moldfile that the line was written in, so the lines that an
+/// `extend` recipe adds don't change how the lines it inherits are executed, and vice versa.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub text: String,
+    pub shell: Option<String>,
+}
+
+impl Line {
+    /// Create a Line that doesn't have a shell yet
+    pub fn new(text: String) -> Self {
+        Line { text, shell: None }
+    }
+}
+
+/// A single step of a recipe
+#[derive(Clone)]
+pub enum Command {
+    /// A command line that gets shell split and executed directly unless it has a shell, and
+    /// whether its failure is tolerated
+    Run(Line, bool),
+
+    /// An inline script body and the name of the runtime that executes it
+    Script(String, String),
+
+    /// A script path relative to $MOLD_SOURCE and an optional runtime override
+    File(String, Option<String>),
+}
+
+impl std::fmt::Display for Var {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Var::Value(value) => write!(f, "{}", value),
+            Var::Command(command) => write!(f, "$({})", command),
+            Var::Unset => write!(f, "unset"),
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Command::Run(line, _) => write!(f, "{}", line.text),
+            Command::Script(runtime, _) => write!(f, "script {}", runtime),
+            Command::File(path, _) => write!(f, "file {}", path),
+        }
+    }
+}
+
+/// An interpreter used to execute scripts
+#[derive(Clone)]
+pub struct Runtime {
+    /// The command line to execute, which will have the script path appended
+    pub command: String,
+
+    /// File extension to give generated scripts
+    pub extension: String,
+}
+
+impl Runtime {
+    fn new(command: &str, extension: &str) -> Self {
+        Runtime {
+            command: command.into(),
+            extension: extension.into(),
+        }
+    }
 }
 
 /// Data straight from a file
@@ -97,16 +623,29 @@ pub struct Moldfile {
     /// A list of imported moldfiles
     pub includes: IncludeVec,
 
+    /// A list of local moldfiles to include and the prefixes to include them with, grouped by the
+    /// `include` that matched them
+    pub local_includes: Vec<(Vec<PathBuf>, String)>,
+
     /// A list of recipes
     pub recipes: RecipeMap,
 
+    /// A map of alternate names to the recipes they refer to
+    pub aliases: AliasMap,
+
     /// A list of environment variables
     pub vars: VarMap,
 
+    /// A list of script runtimes
+    pub runtimes: RuntimeMap,
+
     /// Working directory relative to $MOLD_ROOT
     ///
     /// This is overridden by a recipe's `dir`
     pub dir: Option<String>,
+
+    /// Recipes to run when no targets are given
+    pub default_targets: Vec<Target>,
 }
 
 impl Mold {
@@ -116,23 +655,77 @@ impl Mold {
         envs: Vec<String>,
         use_git: bool,
         use_vars: bool,
+        strict: bool,
+        dry: bool,
+        compute_conditions: bool,
     ) -> Result<Mold, Error> {
-        let root_dir = path.parent().unwrap_or(&Path::new("/")).to_path_buf();
-        let mold_dir = root_dir.join(".mold");
+        let mut mold = Self::new(path, envs, use_git, use_vars, strict)?;
+        mold.dry = dry;
+        mold.compute_conditions = compute_conditions;
+        mold.open(path, "", false, &Selection::All, None)?;
+        mold.check_bases()?;
+        Ok(mold)
+    }
 
-        if !mold_dir.is_dir() {
-            fs::create_dir(&mold_dir).map_err(|err| {
-                failure::format_err!(
-                    "Could not create directory {}: {}",
-                    mold_dir.display().to_string().red(),
-                    err
-                )
-            })?;
+    /// Describe where a recipe was defined as a path and line number
+    fn describe_origin((path, span): &(PathBuf, lang::Span)) -> String {
+        match fs::read_to_string(path) {
+            Ok(code) => {
+                let line = code[..span.start.min(code.len())].matches('\n').count() + 1;
+                format!("{}:{}", path.display(), line)
+            }
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    /// Make sure that every override and extension found the recipe it's based on
+    ///
+    /// This can only be done once every moldfile has been loaded, since the base recipe can come
+    /// from any of them.
+    fn check_bases(&self) -> Result<(), Error> {
+        for (name, recipe) in &self.recipes {
+            let verb = match recipe.modifier {
+                Modifier::Override if !recipe.merged => "overrides",
+                Modifier::Extend(_) if !recipe.merged => "extends",
+                _ => continue,
+            };
+
+            let message = format!("Recipe {} {} a recipe that doesn't exist", name, verb);
+            let (path, span) = &recipe.origin;
+            return Err(match fs::read_to_string(path) {
+                Ok(code) => lang::Source { code: &code, path }.error(*span, message),
+                Err(_) => failure::format_err!("{} in {}", message, path.display()),
+            });
         }
 
+        Ok(())
+    }
+
+    /// Create a new, empty application rooted in the directory of the given path
+    pub fn new(
+        path: &Path,
+        envs: Vec<String>,
+        use_git: bool,
+        use_vars: bool,
+        strict: bool,
+    ) -> Result<Mold, Error> {
+        let root_dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let mold_dir = root_dir.join(".mold");
+
         let vars = indexmap! {
-          "MOLD_ROOT".into() => root_dir.to_string_lossy().into(),
-          "MOLD_DIR".into() => mold_dir.to_string_lossy().into(),
+          "MOLD_ROOT".into() => Var::Value(root_dir.to_string_lossy().into()),
+          "MOLD_DIR".into() => Var::Value(mold_dir.to_string_lossy().into()),
+        };
+
+        let runtimes = indexmap! {
+          "sh".into() => Runtime::new("sh", "sh"),
+          "bash".into() => Runtime::new("bash", "sh"),
+          "zsh".into() => Runtime::new("zsh", "zsh"),
+          "python".into() => Runtime::new("python3", "py"),
+          "node".into() => Runtime::new("node", "js"),
+          "ruby".into() => Runtime::new("ruby", "rb"),
+          "perl".into() => Runtime::new("perl", "pl"),
+          "powershell".into() => Runtime::new("pwsh -File", "ps1"),
         };
 
         let envs = envs.into_iter().collect();
@@ -145,35 +738,68 @@ impl Mold {
             )
         })?;
 
-        let mold_dir = fs::canonicalize(&mold_dir).map_err(|err| {
-            failure::format_err!(
-                "Couldn't canonicalize directory {}: {}",
-                mold_dir.display().to_string().red(),
-                err
-            )
-        })?;
+        // the mold dir is only created once something needs to be written into it
+        let mold_dir = root_dir.join(".mold");
 
-        let mut mold = Mold {
+        Ok(Mold {
             root_dir,
             mold_dir,
             recipes: RecipeMap::new(),
             sources: SourceMap::new(),
+            dirs: SourceMap::new(),
+            aliases: AliasMap::new(),
+            alias_sources: SourceMap::new(),
+            files: vec![],
             remotes: vec![],
             work_dir: None,
+            default_targets: vec![],
             envs,
             vars,
+            var_sources: SourceMap::new(),
+            import_vars: IndexMap::new(),
+            computed: RefCell::new(IndexMap::new()),
+            runtimes,
             use_git,
+            fetch: true,
             use_vars,
-        };
+            strict,
+            dry: false,
+            compute_conditions: true,
+            interrupted: Arc::default(),
+            loading: vec![],
+        })
+    }
+
+    /// Survive the first Ctrl-C instead of exiting, so that recipes can stop between commands and
+    /// still run their finally hooks
+    ///
+    /// The running command is interrupted along with mold, since they share a terminal. A second
+    /// Ctrl-C exits immediately, in case a finally hook hangs.
+    pub fn catch_interrupts(&self) -> Result<(), Error> {
+        use signal_hook::consts::SIGINT;
+        signal_hook::flag::register_conditional_shutdown(SIGINT, 130, self.interrupted.clone())?;
+        signal_hook::flag::register(SIGINT, self.interrupted.clone())?;
+        Ok(())
+    }
 
-        mold.open(path, "")?;
+    /// Create the mold dir if it doesn't exist yet
+    fn create_mold_dir(&self) -> Result<(), Error> {
+        if !self.mold_dir.is_dir() {
+            fs::create_dir(&self.mold_dir).map_err(|err| {
+                failure::format_err!(
+                    "Could not create directory {}: {}",
+                    self.mold_dir.display().to_string().red(),
+                    err
+                )
+            })?;
+        }
 
-        Ok(mold)
+        Ok(())
     }
 
     /// Delete all cloned top-level targets
     pub fn clean_all(path: &Path) -> Result<(), Error> {
-        let root_dir = path.parent().unwrap_or(&Path::new("/")).to_path_buf();
+        let root_dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
         let mold_dir = root_dir.join(".mold");
 
         if mold_dir.is_dir() {
@@ -193,8 +819,58 @@ impl Mold {
         Ok(())
     }
 
+    /// Rewrite a moldfile in the canonical style
+    pub fn format(path: &Path) -> Result<(), Error> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            failure::format_err!(
+                "Couldn't read {}: {}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        let source = lang::Source {
+            code: &contents,
+            path,
+        };
+
+        let formatted = fmt::format(&source).map_err(|err| {
+            failure::format_err!(
+                "Couldn't format {}:\n{}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        if formatted == contents {
+            println!("{:>12} {}", "Unchanged".green(), path.display());
+            return Ok(());
+        }
+
+        fs::write(path, formatted).map_err(|err| {
+            failure::format_err!(
+                "Couldn't write {}: {}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        println!("{:>12} {}", "Formatted".green(), path.display());
+
+        Ok(())
+    }
+
     /// Given a path, load the file into the current application
-    fn open(&mut self, path: &Path, prefix: &str) -> Result<(), Error> {
+    ///
+    /// Returns the names of every recipe it defined, the same as `load`.
+    fn open(
+        &mut self,
+        path: &Path,
+        prefix: &str,
+        local: bool,
+        selection: &Selection,
+        seed: Option<VarMap>,
+    ) -> Result<NameSet, Error> {
         let mut file = fs::File::open(path).map_err(|err| {
             failure::format_err!(
                 "Couldn't open {}: {}",
@@ -212,16 +888,52 @@ impl Mold {
             )
         })?;
 
-        let data = self::lang::compile(&contents, self).map_err(|err| {
+        let source = lang::Source {
+            code: &contents,
+            path,
+        };
+
+        self.load(&source, prefix, local, selection, seed)
+    }
+
+    /// Given a moldfile's Source, load it into the current application
+    ///
+    /// This is separate from `open` so that code that hasn't been saved yet can be loaded.
+    ///
+    /// `local` is set for moldfiles that were pulled in with an `include`, whose `dir` statements
+    /// are relative to themselves and only apply to their own recipes. Only the recipes picked by
+    /// `selection` are kept, along with private copies of the ones they require. If there's a
+    /// `seed`, even an empty one, the moldfile's variables are kept separate from the global ones
+    /// and only apply to its own recipes.
+    ///
+    /// Returns the names of every recipe it defined or changed, including the ones from the
+    /// moldfiles it imports or includes.
+    pub fn load(
+        &mut self,
+        source: &lang::Source,
+        prefix: &str,
+        local: bool,
+        selection: &Selection,
+        seed: Option<VarMap>,
+    ) -> Result<NameSet, Error> {
+        let scoped = seed.is_some();
+        let mut defined = NameSet::new();
+        let path = source.path;
+        let root_dir = source.dir().to_path_buf();
+        let file = (path.to_path_buf(), prefix.to_string());
+        let reloaded = self.files.contains(&file);
+        self.files.push(file);
+        self.loading
+            .push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+
+        let data = lang::compile(source, self, seed.unwrap_or_default()).map_err(|err| {
             failure::format_err!(
-                "Couldn't compile {}: {}",
+                "Couldn't compile {}:\n{}",
                 path.display().to_string().red(),
                 err
             )
         })?;
 
-        let root_dir = path.parent().unwrap_or(&Path::new("/")).to_path_buf();
-
         // check version requirements
         let self_version = Version::parse(clap::crate_version!())?;
         let target_version = VersionReq::parse(&data.version).map_err(|err| {
@@ -242,7 +954,21 @@ impl Mold {
             ));
         }
 
-        for (name, recipe) in data.recipes {
+        // a selection applies to everything this moldfile brings in, including what its own imports
+        // and includes define, so it can only be applied once they've all been loaded
+        let before = match selection {
+            Selection::All => None,
+            _ => Some(Snapshot {
+                recipes: self.recipes.clone(),
+                sources: self.sources.clone(),
+                dirs: self.dirs.clone(),
+                import_vars: self.import_vars.clone(),
+                aliases: self.aliases.keys().cloned().collect(),
+            }),
+        };
+
+        let file_dir = &data.dir;
+        for (name, recipe) in data.recipes {
             let new_key = format!("{}{}", prefix, name);
 
             // clone this recipe and prefix all of its dependencies
@@ -250,17 +976,111 @@ impl Mold {
             new_recipe.requires = new_recipe
                 .requires
                 .iter()
-                .map(|x| format!("{}{}", prefix, x))
+                .map(|x| Target {
+                    name: format!("{}{}", prefix, x.name),
+                    args: x.args.clone(),
+                })
                 .collect();
 
-            self.recipes.entry(new_key.clone()).or_insert(new_recipe);
+            if local {
+                new_recipe.dir = new_recipe.dir.or_else(|| file_dir.clone());
+            }
+
+            // the same moldfile can be imported more than once with the same prefix, eg by two
+            // modules that both import it, which defines nothing new
+            if let Some(existing) = self.recipes.get(&new_key) {
+                if reloaded || existing.origin.0 == new_recipe.origin.0 {
+                    defined.insert(new_key);
+                    continue;
+                }
+            }
+
+            // a recipe that's overridden or extended keeps the source of the recipe it's based on
+            let new_source = match self.recipes.remove(&new_key) {
+                Some(existing) => {
+                    let is_base = new_recipe.modifier.is_base(existing.modifier);
+                    let existing_origin = Self::describe_origin(&existing.origin);
+                    let span = new_recipe.origin.1;
+                    new_recipe = existing.merge(new_recipe, &new_key).map_err(|err| {
+                        source.error(
+                            span,
+                            format!("{} (it's also defined at {})", err, existing_origin),
+                        )
+                    })?;
+                    is_base
+                }
+                None => true,
+            };
+
+            self.recipes.insert(new_key.clone(), new_recipe);
+            defined.insert(new_key.clone());
+
+            if !new_source {
+                continue;
+            }
+
+            if local {
+                self.dirs.insert(new_key.clone(), root_dir.clone());
+            } else {
+                self.dirs.shift_remove(&new_key);
+            }
+
+            if scoped {
+                self.import_vars.insert(new_key.clone(), data.vars.clone());
+            } else {
+                self.import_vars.shift_remove(&new_key);
+            }
 
             // keep track of where this recipe came from so it can use things from its repo
-            self.sources.entry(new_key).or_insert(root_dir.clone());
+            self.sources.insert(new_key, root_dir.clone());
+        }
+
+        for (alias, name) in data.aliases {
+            let new_alias = format!("{}{}", prefix, alias);
+            let new_name = format!("{}{}", prefix, name);
+
+            if let Some(existing) = self.aliases.get(&new_alias) {
+                let existing_source = &self.alias_sources[&new_alias];
+
+                // the same moldfile can be imported more than once with the same prefix
+                if *existing == new_name && existing_source == path {
+                    continue;
+                }
+
+                return Err(failure::format_err!(
+                    "Alias {} from {} is already defined in {}",
+                    new_alias.red(),
+                    path.display().to_string().red(),
+                    existing_source.display().to_string().red()
+                ));
+            }
+
+            self.aliases.insert(new_alias.clone(), new_name);
+            self.alias_sources.insert(new_alias, path.to_path_buf());
+        }
+
+        // only the moldfile that mold was started with decides what runs by default
+        if self.files.len() == 1 {
+            self.default_targets = data.default_targets;
+        }
+
+        // make this file's variables visible to the moldfiles it includes, so their conditions and
+        // defaults can see them. they're merged again afterwards so that they still take precedence.
+        // scoped variables were already given to this file's recipes instead
+        if !scoped {
+            self.vars.extend(data.vars.clone());
         }
 
         for include in data.includes {
             if !include.remote.exists(&self.mold_dir) {
+                if !self.fetch {
+                    return Err(failure::format_err!(
+                        "Module {} hasn't been cloned yet",
+                        include.remote.url.red()
+                    ));
+                }
+
+                self.create_mold_dir()?;
                 include
                     .remote
                     .pull(&self.mold_dir, self.use_git)
@@ -283,17 +1103,146 @@ impl Mold {
             let path = include.remote.path(&self.mold_dir);
             self.remotes.push(include.remote.clone());
             let filepath = Self::discover(&path, include.remote.file)?;
-            self.open(&filepath, &include.prefix)?;
+            defined.extend(self.open(
+                &filepath,
+                &include.prefix,
+                false,
+                &include.selection,
+                include.vars,
+            )?);
+        }
+
+        for (include_paths, include_prefix) in data.local_includes {
+            let include_prefix = format!("{}{}", prefix, include_prefix);
+
+            // the moldfiles matched by a single glob can't define the same recipe, since none of
+            // them is more deliberate than the others
+            let mut owners: IndexMap<String, PathBuf> = IndexMap::new();
+
+            for include_path in include_paths {
+                // moldfiles can include each other, or a glob that matches themselves, so a
+                // moldfile that's still being loaded is never included again. the same moldfile
+                // can be included under different prefixes, but only once under each one
+                let canonical = fs::canonicalize(&include_path).unwrap_or(include_path.clone());
+                let loaded = self.loading.contains(&canonical)
+                    || self.files.iter().any(|(file, file_prefix)| {
+                        *file_prefix == include_prefix
+                            && fs::canonicalize(file).as_ref().unwrap_or(file) == &canonical
+                    });
+
+                if loaded {
+                    continue;
+                }
+
+                let names =
+                    self.open(&include_path, &include_prefix, true, &Selection::All, None)?;
+
+                for name in names {
+                    if let Some(other) = owners.get(&name) {
+                        return Err(failure::format_err!(
+                            "Recipe {} is defined by both {} and {}, which are included together",
+                            name.red(),
+                            other.display().to_string().red(),
+                            include_path.display().to_string().red()
+                        ));
+                    }
+                    owners.insert(name.clone(), include_path.clone());
+                    defined.insert(name);
+                }
+            }
         }
 
-        self.vars.extend(data.vars);
+        if !scoped {
+            // keep track of where each variable came from so failures can be traced back to it
+            for name in data.vars.keys() {
+                self.var_sources.insert(name.clone(), path.to_path_buf());
+            }
+
+            self.vars.extend(data.vars);
+        }
+        self.runtimes.extend(data.runtimes);
 
-        // if this file has a `dir` stmt, it overrides any other dir that was set
-        if let Some(rel_path) = data.dir {
+        // if this file has a `dir` stmt, it overrides any other dir that was set. local includes
+        // have already applied theirs to their own recipes
+        if let Some(rel_path) = data.dir.filter(|_| !local) {
             self.work_dir = Some(rel_path);
         }
 
-        Ok(())
+        if let Some(before) = before {
+            defined = self
+                .apply_selection(selection, prefix, defined, before)
+                .map_err(|err| {
+                    failure::format_err!(
+                        "Couldn't import from {}: {}",
+                        path.display().to_string().red(),
+                        err
+                    )
+                })?;
+        }
+
+        self.loading.pop();
+        Ok(defined)
+    }
+
+    /// Drop the recipes that an import defined but its selection doesn't pick, returning the ones
+    /// that are left
+    ///
+    /// Recipes that are only kept because a picked recipe requires them are made private. Dropped
+    /// recipes that the import overrode or extended are put back the way they were `before` it,
+    /// along with any aliases that point at what was dropped.
+    fn apply_selection(
+        &mut self,
+        selection: &Selection,
+        prefix: &str,
+        defined: NameSet,
+        before: Snapshot,
+    ) -> Result<NameSet, Error> {
+        let (picked, required) =
+            selection.select(prefix, &defined, &self.recipes, &self.aliases)?;
+
+        for name in &required {
+            if let Some(recipe) = self.recipes.get_mut(name) {
+                recipe.private = true;
+            }
+        }
+
+        for name in defined
+            .iter()
+            .filter(|x| !picked.contains(*x) && !required.contains(*x))
+        {
+            match before.recipes.get(name) {
+                Some(recipe) => self.recipes.insert(name.clone(), recipe.clone()),
+                None => self.recipes.remove(name),
+            };
+
+            for (map, old) in [
+                (&mut self.sources, &before.sources),
+                (&mut self.dirs, &before.dirs),
+            ] {
+                match old.get(name) {
+                    Some(path) => map.insert(name.clone(), path.clone()),
+                    None => map.shift_remove(name),
+                };
+            }
+
+            match before.import_vars.get(name) {
+                Some(vars) => self.import_vars.insert(name.clone(), vars.clone()),
+                None => self.import_vars.shift_remove(name),
+            };
+        }
+
+        let dropped: NameSet = self
+            .aliases
+            .iter()
+            .filter(|(alias, name)| !before.aliases.contains(*alias) && !picked.contains(*name))
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        for alias in &dropped {
+            self.aliases.shift_remove(alias);
+            self.alias_sources.shift_remove(alias);
+        }
+
+        Ok(picked.into_iter().chain(required).collect())
     }
 
     /// Try to find a file by walking up the tree
@@ -361,107 +1310,801 @@ impl Mold {
         }
     }
 
-    /// Look up a recipe by name
+    /// Look up the name of the recipe that a name refers to, which is the name itself unless it's
+    /// an alias
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        if self.recipes.contains_key(name) {
+            return name;
+        }
+
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
+    /// Check whether a name refers to a recipe, either directly or as an alias
+    pub fn has_recipe(&self, name: &str) -> bool {
+        self.recipes.contains_key(self.resolve(name))
+    }
+
+    /// Look up a recipe by name or alias
     fn recipe(&self, name: &str) -> Result<&Recipe, Error> {
         self.recipes
-            .get(name)
+            .get(self.resolve(name))
             .ok_or_else(|| failure::format_err!("Couldn't find recipe {}", name.red()))
     }
 
-    /// Construct a Task instance from a recipe name
-    fn build_task(&self, name: &str) -> Result<Task, Error> {
-        let recipe = self.recipe(name)?;
+    /// Resolve a Target's arguments against its recipe's parameters
+    ///
+    /// The resulting Target refers to the recipe itself rather than an alias, and
+    /// only contains named arguments, in parameter order, with defaults filled in.
+    /// This means that two Targets referring to the same invocation compare as
+    /// equal regardless of how they were written. Arguments are literal values,
+    /// but defaults have their variables expanded.
+    fn bind(&self, target: &Target) -> Result<Target, Error> {
+        let recipe = self.recipe(&target.name)?;
+        let mut values: Vec<Option<String>> = vec![None; recipe.params.len()];
+        let mut positional = 0;
+
+        for arg in &target.args {
+            let (idx, value) = match arg {
+                Arg::Positional(value) => {
+                    if positional >= recipe.params.len() {
+                        return Err(failure::format_err!(
+                            "Recipe {} takes {} argument(s), but more were given",
+                            target.name.red(),
+                            recipe.params.len()
+                        ));
+                    }
+                    positional += 1;
+                    (positional - 1, value)
+                }
+
+                Arg::Named(name, value) => {
+                    let idx = recipe
+                        .params
+                        .iter()
+                        .position(|param| &param.name == name)
+                        .ok_or_else(|| {
+                            failure::format_err!(
+                                "Recipe {} has no parameter named {}",
+                                target.name.red(),
+                                name.red()
+                            )
+                        })?;
+                    (idx, value)
+                }
+            };
 
-        // expand all variables
-        let mut vars = VarMap::new();
-        for (name, value) in &self.vars {
-            vars.insert(name.clone(), self.expand(value, &vars).into());
+            if values[idx].is_some() {
+                return Err(failure::format_err!(
+                    "Recipe {} received argument {} more than once",
+                    target.name.red(),
+                    recipe.params[idx].name.red()
+                ));
+            }
+            values[idx] = Some(value.clone());
+        }
+
+        let name = self.resolve(&target.name);
+        let mut base_vars = None;
+        let mut args = vec![];
+        for (param, value) in recipe.params.iter().zip(values) {
+            let value = match (value, &param.default) {
+                (Some(value), _) => value,
+
+                // defaults come from the moldfile, so unlike arguments, they can refer to its
+                // variables
+                (None, Some(default)) if default.contains('$') => {
+                    let vars = match &base_vars {
+                        Some(vars) => vars,
+                        None => base_vars.insert(self.base_vars(name)?),
+                    };
+
+                    self.expand(default, vars)
+                        .map_err(|err| {
+                            failure::format_err!(
+                                "Couldn't bind argument {} for recipe {}: {}",
+                                param.name.red(),
+                                name.red(),
+                                err
+                            )
+                        })?
+                        .into_owned()
+                }
+
+                (None, Some(default)) => default.clone(),
+
+                (None, None) => {
+                    return Err(failure::format_err!(
+                        "Recipe {} is missing required argument {}",
+                        target.name.red(),
+                        param.name.red()
+                    ))
+                }
+            };
+            args.push(Arg::Named(param.name.clone(), value));
         }
 
+        Ok(Target {
+            name: name.to_string(),
+            args,
+        })
+    }
+
+    /// Convert a list of command line arguments into a list of Targets
+    ///
+    /// Each recipe name may be followed by arguments for it, either as
+    /// `name=value` pairs or positionally. Positional arguments are consumed
+    /// while the recipe still has unfilled parameters, unless all of its
+    /// required parameters are filled and the argument names another recipe.
+    ///
+    /// Private recipes are rejected unless `all` is set.
+    pub fn parse_targets(&self, args: &[String], all: bool) -> Result<Vec<Target>, Error> {
+        let mut targets: Vec<Target> = vec![];
+
+        for arg in args {
+            if let Some(target) = targets.last_mut() {
+                let recipe = self.recipe(&target.name)?;
+
+                if let Some((name, value)) = arg.split_once('=') {
+                    if recipe.params.iter().any(|param| param.name == name) {
+                        target.args.push(Arg::Named(name.into(), value.into()));
+                        continue;
+                    }
+                }
+
+                let required = recipe
+                    .params
+                    .iter()
+                    .filter(|param| param.default.is_none())
+                    .count();
+                let filled = target.args.len();
+
+                if filled < required || (filled < recipe.params.len() && !self.has_recipe(arg)) {
+                    target.args.push(Arg::Positional(arg.clone()));
+                    continue;
+                }
+            }
+
+            targets.push(Target::new(arg));
+        }
+
+        // private recipes can only be named directly when they've been asked for
+        if !all {
+            for target in &targets {
+                if self.recipe(&target.name)?.private {
+                    return Err(failure::format_err!(
+                        "Recipe {} is private; pass --all to run it directly",
+                        target.name.red()
+                    ));
+                }
+            }
+        }
+
+        Ok(targets)
+    }
+
+    /// Construct a Task instance from a bound Target
+    fn build_task(&self, target: &Target) -> Result<Task, Error> {
+        let mut task = self.prepare_task(target)?;
+        task.commands = self.build_commands(&target.name, &task.vars)?;
+        Ok(task)
+    }
+
+    /// Evaluate the variables that every task of a recipe starts with, before its arguments and
+    /// its own variables are bound
+    fn base_vars(&self, name: &str) -> Result<ValueMap, Error> {
+        // expand all variables
+        let mut vars = self.evaluate_vars()?;
+
         // insert var for where this recipe's moldfile lives
-        if let Some(source) = self.sources.get(name) {
-            vars.insert("MOLD_SOURCE".into(), source.to_string_lossy().into());
-        } else {
-            return Err(failure::format_err!(
-                "Couldn't find source repository for {}",
-                name.red()
-            ));
+        let source = self.sources.get(name).ok_or_else(|| {
+            failure::format_err!("Couldn't find source repository for {}", name.red())
+        })?;
+        vars.insert("MOLD_SOURCE".into(), Some(source.to_string_lossy().into()));
+
+        // variables that were passed into this recipe's import, along with that moldfile's own
+        if let Some(import_vars) = self.import_vars.get(name) {
+            let origin = format!("the import of {}", source.display());
+            for (var_name, var) in import_vars {
+                let value = self.evaluate(var_name, var, &vars, &origin)?;
+                vars.insert(var_name.clone(), value);
+            }
         }
 
-        // select the recipe's working dir if it's defined, otherwise select the Mold's working dir. in
-        // both cases, we want to expand the variables afterwards and join it with $MOLD_ROOT. if
-        // neither dir is defined, the command will default to the current working dir.
-        let work_dir = recipe
-            .dir
-            .clone()
-            .or_else(|| self.work_dir.clone())
-            .map(|raw_path| {
-                self.root_dir
-                    .join(self.expand(&raw_path, &vars).to_string())
-            });
+        Ok(vars)
+    }
 
-        // build the command strings to execute
-        let mut commands = vec![];
-        for command_str in &recipe.commands {
-            let args = self.build_args(command_str, &vars)?;
-            if args.is_empty() {
+    /// Construct a Task instance from a bound Target, with its variables and working dir but no
+    /// commands yet
+    fn prepare_task(&self, target: &Target) -> Result<Task, Error> {
+        let name = target.name.as_str();
+        let recipe = self.recipe(name)?;
+        let mut vars = self.base_vars(name)?;
+
+        // bind all arguments as variables. they're taken literally, since any defaults were
+        // already expanded when the target was bound
+        for arg in &target.args {
+            if let Arg::Named(param, value) = arg {
+                vars.insert(param.clone(), Some(value.clone()));
+            }
+        }
+
+        // recipe variables override globals, unless they're defaults
+        let origin = format!("recipe {}", name);
+        for (var_name, var) in &recipe.vars {
+            let defined = match vars.get(var_name) {
+                Some(value) => value.is_some(),
+                None => std::env::var(var_name).is_ok(),
+            };
+
+            if recipe.defaults.contains(var_name) && defined {
                 continue;
             }
-            commands.push(args);
+
+            let value = self.evaluate(var_name, var, &vars, &origin)?;
+            vars.insert(var_name.clone(), value);
         }
 
+        // select the recipe's working dir if it's defined, otherwise select the Mold's working dir. in
+        // both cases, we want to expand the variables afterwards and join it with $MOLD_ROOT, or
+        // the recipe's own moldfile if it was included locally. if neither dir is defined, the
+        // command will default to the current working dir.
+        let work_dir = match (&recipe.dir, &self.work_dir) {
+            (Some(raw_path), _) => Some((raw_path, self.dirs.get(name).unwrap_or(&self.root_dir))),
+            (None, Some(raw_path)) => Some((raw_path, &self.root_dir)),
+            (None, None) => None,
+        };
+
+        let work_dir = match work_dir {
+            Some((raw_path, base_dir)) => {
+                let path = self.expand(raw_path, &vars).map_err(|err| {
+                    failure::format_err!(
+                        "Couldn't expand working dir for recipe {}: {}",
+                        name.red(),
+                        err
+                    )
+                })?;
+                Some(base_dir.join(path.to_string()))
+            }
+            None => None,
+        };
+
         Ok(Task {
-            name: name.into(),
-            commands,
+            name: target.to_string(),
+            commands: vec![],
             vars,
             work_dir,
+            allowed_exit: recipe.allowed_exit.clone(),
+            interrupted: self.interrupted.clone(),
         })
     }
 
-    /// Construct and execute a Task from a recipe name
-    pub fn execute(&self, name: &str) -> Result<(), Error> {
-        let task = self.build_task(name)?;
-        task.execute()
+    /// Build the command strings to execute, surrounded by the recipe's before and after hooks
+    fn build_commands(
+        &self,
+        name: &str,
+        vars: &ValueMap,
+    ) -> Result<Vec<(Vec<String>, bool)>, Error> {
+        let recipe = self.recipe(name)?;
+        let source = self.sources.get(name).ok_or_else(|| {
+            failure::format_err!("Couldn't find source repository for {}", name.red())
+        })?;
+
+        let hook = |args| (args, recipe.ignore_errors);
+        let before = self.build_hooks(name, &recipe.hooks.before, vars)?;
+        let mut commands: Vec<_> = before.into_iter().map(hook).collect();
+        for command in &recipe.commands {
+            let tolerant = recipe.ignore_errors || matches!(command, Command::Run(_, true));
+            let args = match command {
+                Command::Run(line, _) => self.build_command(line, vars),
+                Command::Script(runtime_name, body) => {
+                    let runtime = self.runtime(runtime_name);
+                    let script = self.write_script(&runtime, body)?;
+                    self.build_args(&runtime.command, vars).map(|mut args| {
+                        args.push(script.to_string_lossy().into());
+                        args
+                    })
+                }
+                Command::File(raw_path, runtime_name) => self
+                    .expand(raw_path, vars)
+                    .map(|path| source.join(path.to_string()))
+                    .and_then(|path| self.build_file_args(&path, runtime_name.as_deref(), vars)),
+            };
+
+            let args = args.map_err(|err| {
+                failure::format_err!(
+                    "Couldn't build command {} for recipe {}: {}",
+                    command.to_string().red(),
+                    name.red(),
+                    err
+                )
+            })?;
+
+            if args.is_empty() {
+                continue;
+            }
+            commands.push((args, tolerant));
+        }
+        let after = self.build_hooks(name, &recipe.hooks.after, vars)?;
+        commands.extend(after.into_iter().map(hook));
+
+        Ok(commands)
+    }
+
+    /// Build the arguments for a command line, passing it to a shell if there is one
+    ///
+    /// Command lines given to a shell are mostly left unexpanded, since the shell can see every
+    /// variable in its environment. See `shell_command` for what's done to them beforehand.
+    fn build_command(&self, line: &Line, vars: &ValueMap) -> Result<Vec<String>, Error> {
+        match &line.shell {
+            Some(shell) => {
+                let mut args = self.build_args(shell, vars)?;
+                args.push(self.shell_command(&line.text, vars)?);
+                Ok(args)
+            }
+            None => self.build_args(&line.text, vars),
+        }
+    }
+
+    /// Prepare a command line to be passed to a shell as a single argument
+    ///
+    /// `$$` is unescaped into `$` the same as it is during expansion, which raw strings rely on.
+    /// In strict mode, every variable it refers to must still be defined, so variables that only
+    /// exist inside the shell need to be escaped as `$$NAME`. Splats like `$@NAME` are replaced
+    /// with the variable's value, shell split and quoted back into separate words.
+    fn shell_command(&self, command: &str, vars: &ValueMap) -> Result<String, Error> {
+        if self.strict {
+            for name in lang::required_var_refs(command) {
+                self.expand(&format!("${{{}}}", name), vars)?;
+            }
+        }
+
+        let pattern =
+            regex::Regex::new(r"\$\$|\$@(?:\{([A-Za-z0-9_]+)\}|([A-Za-z0-9_]+))").unwrap();
+        let mut result = String::new();
+        let mut last = 0;
+        for captures in pattern.captures_iter(command) {
+            let found = captures.get(0).unwrap();
+            result.push_str(&command[last..found.start()]);
+            last = found.end();
+
+            match captures.get(1).or_else(|| captures.get(2)) {
+                Some(name) => {
+                    let splat = format!("${{{}}}", name.as_str());
+                    let value = self.expand(&splat, vars)?;
+                    let words = shell_words::split(&value).map_err(|err| {
+                        failure::format_err!("Couldn't shell split string {}: {}", value.red(), err)
+                    })?;
+                    result.push_str(&shell_words::join(words));
+                }
+                None => result.push('$'),
+            }
+        }
+        result.push_str(&command[last..]);
+
+        Ok(result)
+    }
+
+    /// Build the command strings for a list of hook commands
+    fn build_hooks(
+        &self,
+        name: &str,
+        hooks: &[Line],
+        vars: &ValueMap,
+    ) -> Result<Vec<Vec<String>>, Error> {
+        let mut commands = vec![];
+        for line in hooks {
+            let args = self.build_command(line, vars).map_err(|err| {
+                failure::format_err!(
+                    "Couldn't build command {} for recipe {}: {}",
+                    line.text.red(),
+                    name.red(),
+                    err
+                )
+            })?;
+
+            if !args.is_empty() {
+                commands.push(args);
+            }
+        }
+
+        Ok(commands)
+    }
+
+    /// Construct and execute a Task from a bound Target
+    ///
+    /// The recipe's finally hooks run afterwards no matter how the task went, including when its
+    /// commands couldn't be built or it was interrupted, and they can see its exit status as
+    /// $MOLD_STATUS. They only can't run if the recipe's variables couldn't be evaluated.
+    pub fn execute(&self, target: &Target) -> Result<(), Error> {
+        let mut task = self.prepare_task(target)?;
+        let result = match self.build_commands(&target.name, &task.vars) {
+            Ok(commands) => {
+                task.commands = commands;
+                task.execute()
+            }
+            Err(err) => Err((1, err)),
+        };
+
+        let recipe = self.recipe(&target.name)?;
+        let finally = &recipe.hooks.finally;
+        if finally.is_empty() {
+            return result.map_err(|(_, err)| err);
+        }
+
+        let status = match &result {
+            Ok(()) => 0,
+            Err((status, _)) => *status,
+        };
+
+        let mut cleanup = task;
+        cleanup
+            .vars
+            .insert("MOLD_STATUS".into(), Some(status.to_string()));
+
+        // every finally hook gets a chance to run, but only the first failure is reported
+        let mut failure = result.err().map(|(_, err)| err);
+        match self.build_hooks(&target.name, finally, &cleanup.vars) {
+            Ok(commands) => {
+                for args in &commands {
+                    if let Err((_, err)) = cleanup.run(args, recipe.ignore_errors) {
+                        failure = failure.or(Some(err));
+                    }
+                }
+            }
+            Err(err) => failure = failure.or(Some(err)),
+        }
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Look up a runtime by name
+    ///
+    /// Unknown names are treated as the command line of an anonymous runtime, so
+    /// `script "python3 -u" "..."` works without defining a runtime first.
+    fn runtime(&self, name: &str) -> Runtime {
+        self.runtimes
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Runtime::new(name, "tmp"))
+    }
+
+    /// Build the arguments needed to execute a script file
+    ///
+    /// If no runtime is explicitly requested, the file's shebang is used first,
+    /// followed by any runtime whose extension matches the file's. Files with
+    /// neither are executed directly.
+    fn build_file_args(
+        &self,
+        path: &Path,
+        runtime_name: Option<&str>,
+        vars: &ValueMap,
+    ) -> Result<Vec<String>, Error> {
+        if !path.is_file() {
+            return Err(failure::format_err!(
+                "Couldn't find script file {}",
+                path.display().to_string().red()
+            ));
+        }
+
+        let mut args = match runtime_name {
+            Some(runtime_name) => self.build_args(&self.runtime(runtime_name).command, vars)?,
+            None => match Self::shebang(path)? {
+                Some(line) => self.build_args(&line, vars)?,
+                None => {
+                    let extension = path.extension().map(|x| x.to_string_lossy());
+                    let runtime = self
+                        .runtimes
+                        .values()
+                        .find(|runtime| Some(runtime.extension.as_str()) == extension.as_deref());
+                    match runtime {
+                        Some(runtime) => self.build_args(&runtime.command, vars)?,
+                        None => vec![],
+                    }
+                }
+            },
+        };
+
+        args.push(path.to_string_lossy().into());
+        Ok(args)
+    }
+
+    /// Read the interpreter line out of a script file, if it has one
+    fn shebang(path: &Path) -> Result<Option<String>, Error> {
+        let contents = fs::read(path).map_err(|err| {
+            failure::format_err!(
+                "Couldn't read script file {}: {}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        let first_line = contents.split(|&x| x == b'\n').next().unwrap_or(&[]);
+        let first_line = String::from_utf8_lossy(first_line);
+
+        Ok(first_line
+            .strip_prefix("#!")
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty()))
+    }
+
+    /// Write a script body into the mold dir and return its path
+    ///
+    /// Scripts are named after a hash of their contents, so identical bodies
+    /// share a single file. In dry mode only the path is returned.
+    fn write_script(&self, runtime: &Runtime, body: &str) -> Result<PathBuf, Error> {
+        let path = self.mold_dir.join(format!(
+            "script-{}.{}",
+            util::hash_string(body),
+            runtime.extension
+        ));
+
+        if self.dry {
+            return Ok(path);
+        }
+
+        self.create_mold_dir()?;
+        fs::write(&path, body).map_err(|err| {
+            failure::format_err!(
+                "Couldn't write script {}: {}",
+                path.display().to_string().red(),
+                err
+            )
+        })?;
+
+        Ok(path)
+    }
+
+    /// Evaluate all variables in order, so that each can refer to the ones before it
+    fn evaluate_vars(&self) -> Result<ValueMap, Error> {
+        let mut values = ValueMap::new();
+        for (name, var) in &self.vars {
+            let value = self.evaluate(name, var, &values, &self.var_origin(name))?;
+            values.insert(name.clone(), value);
+        }
+
+        Ok(values)
+    }
+
+    /// Describe where a global variable was defined
+    fn var_origin(&self, name: &str) -> String {
+        match self.var_sources.get(name) {
+            Some(path) => path.display().to_string(),
+            None => "<unknown>".into(),
+        }
+    }
+
+    /// Evaluate a single variable given the values defined before it
+    ///
+    /// `origin` describes where the variable was defined for error messages.
+    fn evaluate(
+        &self,
+        name: &str,
+        var: &Var,
+        values: &ValueMap,
+        origin: &str,
+    ) -> Result<Option<String>, Error> {
+        match var {
+            Var::Value(value) => {
+                let value = self.expand(value, values).map_err(|err| {
+                    failure::format_err!(
+                        "Couldn't expand variable {} from {}: {}",
+                        name.red(),
+                        origin.red(),
+                        err
+                    )
+                })?;
+                Ok(Some(value.into()))
+            }
+            Var::Command(command) => self.compute(name, command, values, origin).map(Some),
+            Var::Unset => Ok(None),
+        }
+    }
+
+    /// Evaluate a single variable that a condition depends on
+    ///
+    /// This is the same as `evaluate`, except computed variables are executed in dry mode too if
+    /// `compute_conditions` is set.
+    fn evaluate_for_condition(
+        &self,
+        name: &str,
+        var: &Var,
+        values: &ValueMap,
+        origin: &str,
+    ) -> Result<Option<String>, Error> {
+        match var {
+            Var::Command(command) if self.dry && self.compute_conditions => {
+                self.run_computed(name, command, values, origin).map(Some)
+            }
+            _ => self.evaluate(name, var, values, origin),
+        }
+    }
+
+    /// Execute a computed variable's command and capture its output
+    ///
+    /// Commands are executed from $MOLD_ROOT and their results are cached for the
+    /// rest of the run by their expanded arguments and environment, so each one is
+    /// executed at most once for the same inputs. In dry mode the command isn't
+    /// executed and its unexpanded `$(...)` text is used instead.
+    fn compute(
+        &self,
+        name: &str,
+        command: &str,
+        values: &ValueMap,
+        source: &str,
+    ) -> Result<String, Error> {
+        if self.dry {
+            return Ok(format!("$({})", command));
+        }
+
+        self.run_computed(name, command, values, source)
+    }
+
+    /// Execute a computed variable's command regardless of dry mode
+    fn run_computed(
+        &self,
+        name: &str,
+        command: &str,
+        values: &ValueMap,
+        source: &str,
+    ) -> Result<String, Error> {
+        let args = self.build_args(command, values)?;
+        if args.is_empty() {
+            return Err(failure::format_err!(
+                "Variable {} from {} has an empty command",
+                name.red(),
+                source.red()
+            ));
+        }
+
+        // the same command can see different variables, eg when it's imported with different
+        // `with` values, so those are part of the key too
+        let envs: Vec<_> = values
+            .iter()
+            .filter_map(|(k, v)| v.clone().map(|v| (k.clone(), v)))
+            .collect();
+        let key = (args, envs);
+        if let Some(value) = self.computed.borrow().get(&key) {
+            return Ok(value.clone());
+        }
+
+        let (args, envs) = &key;
+        log::debug!("Computing variable {} from {}", name, source);
+        let output = process::Command::new(&args[0])
+            .args(&args[1..])
+            .envs(envs.iter().cloned())
+            .current_dir(&self.root_dir)
+            .stderr(process::Stdio::inherit())
+            .output()
+            .map_err(|err| {
+                failure::format_err!(
+                    "Couldn't compute variable {} from {}: {}",
+                    name.red(),
+                    source.red(),
+                    err
+                )
+            })?;
+
+        if !output.status.success() {
+            return Err(failure::format_err!(
+                "Variable {} from {} returned non-zero exit status",
+                name.red(),
+                source.red()
+            ));
+        }
+
+        // strip trailing newlines, the same as a shell's $(...)
+        let value = String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches(['\n', '\r'])
+            .to_string();
+        self.computed.borrow_mut().insert(key, value.clone());
+
+        Ok(value)
     }
 
     /// Perform variable expansion on a string
-    fn expand<'a>(&self, val: &'a str, vars: &VarMap) -> std::borrow::Cow<'a, str> {
-        shellexpand::env_with_context_no_errors(val, |name| {
-            vars.get(name)
-                .map(std::string::ToString::to_string)
-                .or_else(|| std::env::var(name).ok())
-                .or_else(|| Some("".into()))
+    ///
+    /// Undefined variables expand to an empty string, unless strict mode is
+    /// enabled, in which case they're an error.
+    fn expand<'a>(
+        &self,
+        val: &'a str,
+        vars: &ValueMap,
+    ) -> Result<std::borrow::Cow<'a, str>, Error> {
+        shellexpand::env_with_context(val, |name| {
+            let value = match vars.get(name) {
+                Some(value) => value.clone(),
+                None => std::env::var(name).ok(),
+            };
+
+            match value {
+                Some(value) => Ok(Some(value)),
+                None if self.strict => Err(failure::format_err!("undefined variable")),
+                None => Ok(Some("".into())),
+            }
+        })
+        .map_err(|err| {
+            failure::format_err!(
+                "Variable {} is not defined",
+                format!("${}", err.var_name).red()
+            )
         })
     }
 
-    /// Perform variable expansion on a string and return a list of arguments to
-    /// pass to std::process::Command
-    fn build_args(&self, command: &str, vars: &VarMap) -> Result<Vec<String>, Error> {
-        let expanded = self.expand(command, vars);
-        Ok(shell_words::split(&expanded).map_err(|err| {
-            failure::format_err!("Couldn't shell split string {}: {}", expanded.red(), err)
-        })?)
+    /// Shell split a string and perform variable expansion on each argument, returning a list of
+    /// arguments to pass to std::process::Command
+    ///
+    /// Variables always expand into a single argument, unless the whole argument is a splat like
+    /// `$@NAME` or `$@{NAME}`, in which case the variable's value is shell split into as many
+    /// arguments as it holds.
+    fn build_args(&self, command: &str, vars: &ValueMap) -> Result<Vec<String>, Error> {
+        let split = |text: &str| {
+            shell_words::split(text).map_err(|err| {
+                failure::format_err!("Couldn't shell split string {}: {}", text.red(), err)
+            })
+        };
+
+        let mut args = vec![];
+        for word in split(command)? {
+            match splat(&word) {
+                Some(name) => args.extend(split(&self.expand(&format!("${{{}}}", name), vars)?)?),
+                None => args.push(self.expand(&word, vars)?.into_owned()),
+            }
+        }
+
+        Ok(args)
     }
 
     /// Find *all* dependencies for a given set of target recipes
-    pub fn find_all_dependencies(&self, targets: &TargetSet) -> Result<TargetSet, Error> {
+    ///
+    /// Every returned Target is bound, so the same recipe invoked with different
+    /// arguments is treated as a separate dependency.
+    pub fn find_all_dependencies<'a, I>(&self, targets: I) -> Result<TargetSet, Error>
+    where
+        I: IntoIterator<Item = &'a Target>,
+    {
         let mut new_targets = TargetSet::new();
+        let mut path = vec![];
 
-        // FIXME this might not break on weird infinite cycles
-        // ...but since those shouldn't happen in sanely written moldfiles...
-        for name in targets {
-            new_targets.extend(self.find_dependencies(name)?);
-            new_targets.insert(name.clone());
+        for target in targets {
+            self.find_dependencies(target, &mut path, &mut new_targets)?;
         }
 
         Ok(new_targets)
     }
 
-    /// Find all recipes for a *single* target recipe
-    fn find_dependencies(&self, name: &str) -> Result<TargetSet, Error> {
-        let recipe = self.recipe(name)?;
-        let deps = recipe.requires.iter().map(ToString::to_string).collect();
-        self.find_all_dependencies(&deps)
+    /// Find all recipes for a *single* target recipe, followed by the target itself
+    ///
+    /// `path` is the chain of recipes that required this one, which is used to detect cycles.
+    fn find_dependencies(
+        &self,
+        target: &Target,
+        path: &mut Vec<String>,
+        found: &mut TargetSet,
+    ) -> Result<(), Error> {
+        let target = self.bind(target)?;
+
+        if let Some(idx) = path.iter().position(|x| *x == target.name) {
+            let mut cycle = path[idx..].to_vec();
+            cycle.push(target.name);
+            return Err(failure::format_err!(
+                "Recipes require each other in a cycle: {}",
+                cycle.join(" -> ").red()
+            ));
+        }
+
+        let recipe = self.recipe(&target.name)?;
+        path.push(target.name.clone());
+        for dependency in &recipe.requires {
+            self.find_dependencies(dependency, path, found)?;
+        }
+        path.pop();
+
+        found.insert(target);
+        Ok(())
     }
 
     /// Update (ie: fetch + force checkout) all remotes
@@ -481,30 +2124,82 @@ impl Mold {
     }
 
     /// Print a short description of all recipes in this moldfile
-    pub fn help(&self) -> Result<(), Error> {
-        for (name, recipe) in &self.recipes {
+    ///
+    /// Recipes are shown as a tree, with the recipes in each group (eg, `docker/build`) listed
+    /// under a heading for that group. Private recipes are left out unless `all` is set.
+    pub fn help(&self, all: bool) -> Result<(), Error> {
+        // list each group's own recipes before the groups inside it
+        let mut names: Vec<_> = self
+            .recipes
+            .iter()
+            .filter(|(_, recipe)| all || !recipe.private)
+            .map(|(name, _)| {
+                let mut path: Vec<_> = name.split('/').collect();
+                let short = path.pop().unwrap_or_default();
+                (path, short, name)
+            })
+            .collect();
+        names.sort();
+
+        let mut prev = vec![];
+        for (path, short, name) in names {
+            let recipe = &self.recipes[name];
+
+            // print a heading for each group that wasn't open already
+            let shared = prev.iter().zip(&path).take_while(|(a, b)| a == b).count();
+            for (depth, part) in path.iter().enumerate().skip(shared) {
+                let heading = format!("{}/", part);
+                println!("{}{:>12}", "  ".repeat(depth), heading.white());
+            }
+            let indent = "  ".repeat(path.len());
+            prev = path;
+
             let help_str = match &recipe.help {
                 Some(x) => x,
                 None => "",
             };
-            println!("{:>12} {}", name.cyan(), help_str);
+            println!(
+                "{}{:>12} {}",
+                indent,
+                recipe.signature(short).cyan(),
+                help_str
+            );
+
+            // print aliases
+            let aliases: Vec<_> = self
+                .aliases
+                .iter()
+                .filter(|(_, target)| *target == name)
+                .map(|(alias, _)| alias.as_str())
+                .collect();
+            if !aliases.is_empty() {
+                println!("{}             = {}", indent, aliases.join(" ").cyan());
+            }
 
             // print dependencies
             let deps: Vec<_> = recipe.requires.iter().map(|x| x.to_string()).collect();
             if !deps.is_empty() {
-                println!("             ⮡ {}", deps.join(" ").cyan());
+                println!("{}             ⮡ {}", indent, deps.join(" ").cyan());
             }
         }
 
         Ok(())
     }
 
+    /// Check all loaded moldfiles for problems and print them
+    pub fn check(&self) -> Result<(), Error> {
+        let problems = check::check(self)?;
+        check::report(&problems, &self.files[0].0)
+    }
+
     /// Print a long description of a recipe
-    pub fn explain(&self, name: &str) -> Result<(), Error> {
+    pub fn explain(&self, target: &Target) -> Result<(), Error> {
         // print recipe information
+        let target = self.bind(target)?;
+        let name = target.name.as_str();
         let recipe = self.recipe(name)?;
 
-        println!("{}", name.cyan());
+        println!("{}", recipe.signature(name).cyan());
         if let Some(help) = &recipe.help {
             if !help.is_empty() {
                 println!("{}", help);
@@ -520,27 +2215,98 @@ impl Mold {
             println!("{} {}", "working dir:".white(), dir.cyan());
         }
 
+        // lines from an extended recipe might run through another moldfile's shell, in which case
+        // each line says which shell it uses
+        let shells: IndexSet<_> = recipe.lines().map(|x| x.shell.as_deref()).collect();
+        let mixed = shells.len() > 1;
+        if let (false, Some(Some(shell))) = (mixed, shells.first()) {
+            println!("{} {}", "shell:".white(), shell.cyan());
+        }
+        let line = |line: &Line| match (&line.shell, mixed) {
+            (Some(shell), true) => format!("{} {}", line.text, format!("(via {})", shell).cyan()),
+            _ => line.text.clone(),
+        };
+
+        if recipe.ignore_errors {
+            println!("{}", "ignores errors".white());
+        }
+
+        if !recipe.allowed_exit.is_empty() {
+            let statuses: Vec<_> = recipe.allowed_exit.iter().map(|x| x.to_string()).collect();
+            println!("{} {}", "allowed exit:".white(), statuses.join(" ").cyan());
+        }
+
+        if !recipe.vars.is_empty() {
+            println!("{}", "recipe variables:".white());
+            for (key, var) in &recipe.vars {
+                let op = if recipe.defaults.contains(key) {
+                    ":="
+                } else {
+                    "="
+                };
+                match var {
+                    Var::Unset => println!("  {} {}", format!("${}", key).bright_cyan(), var),
+                    _ => println!("  {} {} {}", format!("${}", key).bright_cyan(), op, var),
+                }
+            }
+        }
+
         if !recipe.commands.is_empty() {
             println!("{}", "commands:".white());
             for command in &recipe.commands {
-                println!("  {} {}", "$".white(), command);
+                match command {
+                    Command::Run(x, false) => println!("  {} {}", "$".white(), line(x)),
+                    Command::Run(x, true) => println!("  {} {}", "-$".white(), line(x)),
+                    Command::Script(runtime, body) => {
+                        println!("  {} {}", "script".white(), runtime.cyan());
+                        for line in body.lines() {
+                            println!("    {}", line);
+                        }
+                    }
+                    Command::File(path, Some(runtime)) => {
+                        println!("  {} {} as {}", "file".white(), path, runtime.cyan())
+                    }
+                    Command::File(path, None) => println!("  {} {}", "file".white(), path),
+                }
+            }
+        }
+
+        let hooks = &recipe.hooks;
+        for (hook, commands) in &[
+            ("before:", &hooks.before),
+            ("after:", &hooks.after),
+            ("finally:", &hooks.finally),
+        ] {
+            if !commands.is_empty() {
+                println!("{}", hook.white());
+                for command in commands.iter() {
+                    println!("  {} {}", "$".white(), line(command));
+                }
             }
         }
 
         // print task information
-        let task = self.build_task(name)?;
+        let task = self.build_task(&target)?;
 
         if !task.vars.is_empty() {
             println!("{}", "variables:".white());
             for (key, val) in &task.vars {
-                println!("  {} = {}", format!("${}", key).bright_cyan(), val);
+                match val {
+                    Some(val) => println!("  {} = {}", format!("${}", key).bright_cyan(), val),
+                    None => println!(
+                        "  {} {}",
+                        format!("${}", key).bright_cyan(),
+                        "unset".white()
+                    ),
+                }
             }
         }
 
         if !task.commands.is_empty() {
             println!("{}", "executes:".white());
-            for args in &task.commands {
-                println!("  {} {}", "$".green(), shell_words::join(args));
+            for (args, tolerant) in &task.commands {
+                let prompt = if *tolerant { "-$" } else { "$" };
+                println!("  {} {}", prompt.green(), shell_words::join(args));
             }
         }
 
@@ -553,81 +2319,876 @@ impl Mold {
     pub fn sh_vars(&self) -> Result<(), Error> {
         // expand all variables
         // expanded values are stored in this map so they can be used in later expansions
-        let mut vars = VarMap::new();
-        for (name, value) in &self.vars {
-            let expanded_value = self.expand(value, &vars);
-            println!("export {}={}", name, shell_words::quote(&expanded_value));
-            vars.insert(name.clone(), expanded_value.into());
+        let mut vars = ValueMap::new();
+        for (name, var) in &self.vars {
+            let value = self.evaluate(name, var, &vars, &self.var_origin(name))?;
+            match &value {
+                Some(value) => println!("export {}={}", name, shell_words::quote(value)),
+                None => println!("unset {}", name),
+            }
+            vars.insert(name.clone(), value);
         }
 
         Ok(())
     }
 }
 
+/// Find the name of the variable in an argument that's only a splat, like `$@NAME` or `$@{NAME}`
+fn splat(word: &str) -> Option<&str> {
+    let name = word.strip_prefix("$@")?;
+    let name = name
+        .strip_prefix('{')
+        .and_then(|x| x.strip_suffix('}'))
+        .unwrap_or(name);
+
+    if !name.is_empty() && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+        Some(name)
+    } else {
+        None
+    }
+}
+
 /// An instantiation of a recipe ready for execution
+///
+/// Each command is paired with whether its failure is tolerated.
 struct Task {
     name: String,
-    commands: Vec<Vec<String>>,
+    commands: Vec<(Vec<String>, bool)>,
     work_dir: Option<PathBuf>,
-    vars: VarMap,
+    vars: ValueMap,
+    allowed_exit: Vec<i32>,
+    interrupted: Arc<AtomicBool>,
 }
 
 impl Task {
-    /// Populate a std::process::Command and spawn it
-    fn execute(self) -> Result<(), Error> {
-        for args in &self.commands {
+    /// Run each command in order, stopping at the first one that fails or once mold is interrupted
+    ///
+    /// Failures come with the exit status they should be reported as.
+    fn execute(&self) -> Result<(), (i32, Error)> {
+        for (args, tolerant) in &self.commands {
             if args.is_empty() {
                 continue;
             }
 
-            let mut command = process::Command::new(&args[0]);
-            command.args(&args[1..]);
-            command.envs(&self.vars);
+            self.run(args, *tolerant)?;
 
-            if let Some(dir) = &self.work_dir {
-                command.current_dir(dir);
+            if self.interrupted.load(Ordering::Relaxed) {
+                return Err((
+                    130,
+                    failure::format_err!("Recipe {} was interrupted", self.name.red()),
+                ));
             }
+        }
 
-            println!(
-                "{} {} {} {}",
-                "mold".white(),
-                self.name.cyan(),
-                "$".green(),
-                shell_words::join(args),
-            );
+        Ok(())
+    }
 
-            use std::io::ErrorKind;
-            let exit_status = command
-                .spawn()
-                .and_then(|mut handle| handle.wait())
-                .map_err(|err| match err.kind() {
-                    ErrorKind::NotFound => failure::format_err!(
+    /// Run a single command, reporting failures that are allowed or tolerated instead of
+    /// returning them
+    fn run(&self, args: &[String], tolerant: bool) -> Result<(), (i32, Error)> {
+        let note = match self.spawn(args) {
+            Err((status, _)) if self.allowed_exit.contains(&status) => {
+                format!("allowed exit status {}", status)
+            }
+            Err((status, _)) if tolerant => format!("ignored exit status {}", status),
+            result => return result,
+        };
+
+        println!("{} {} {}", "mold".white(), self.name.cyan(), note.yellow());
+        Ok(())
+    }
+
+    /// Populate a std::process::Command and spawn it
+    fn spawn(&self, args: &[String]) -> Result<(), (i32, Error)> {
+        let mut command = process::Command::new(&args[0]);
+        command.args(&args[1..]);
+
+        for (name, value) in &self.vars {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+
+        if let Some(dir) = &self.work_dir {
+            command.current_dir(dir);
+        }
+
+        println!(
+            "{} {} {} {}",
+            "mold".white(),
+            self.name.cyan(),
+            "$".green(),
+            shell_words::join(args),
+        );
+
+        // commands that can't be started report the same statuses that a shell would
+        use std::io::ErrorKind;
+        let exit_status = command
+            .spawn()
+            .and_then(|mut handle| handle.wait())
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => (
+                    127,
+                    failure::format_err!(
                         "Recipe {} failed because command {} was not found",
                         self.name.red(),
                         args[0].red()
                     ),
+                ),
 
-                    ErrorKind::PermissionDenied => failure::format_err!(
+                ErrorKind::PermissionDenied => (
+                    126,
+                    failure::format_err!(
                         "Recipe {} failed because you do not have permission to execute command {}",
                         self.name.red(),
                         args[0].red()
                     ),
+                ),
 
-                    _ => failure::format_err!(
+                _ => (
+                    1,
+                    failure::format_err!(
                         "Recipe {} failed due to an unknown OS error: {}",
                         self.name.red(),
                         err
                     ),
-                })?;
+                ),
+            })?;
 
-            if !exit_status.success() {
-                return Err(failure::format_err!(
-                    "Recipe {} returned non-zero exit status",
-                    self.name.red()
-                ));
-            }
+        if !exit_status.success() {
+            return Err((
+                exit_code(exit_status),
+                failure::format_err!("Recipe {} returned non-zero exit status", self.name.red()),
+            ));
         }
 
         Ok(())
     }
 }
+
+/// Find the exit status a shell would report for a process, including ones killed by a signal
+fn exit_code(status: process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    status.code().unwrap_or(1)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Write some files into an empty scratch directory, returning its path
+    ///
+    /// Each test's directory is cleared out the next time it runs, rather than piling up.
+    pub fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mold-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, code) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+        dir
+    }
+
+    /// Commit everything in a scratch directory so that it can be imported
+    pub fn commit(dir: &Path) {
+        let repo = git2::Repository::init(dir).unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("mold", "mold@localhost").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .unwrap();
+    }
+
+    /// Load the moldfile in a scratch directory the way --explain does
+    pub fn load(dir: &Path) -> Result<Mold, Error> {
+        Mold::init(
+            &dir.join("moldfile"),
+            vec![],
+            false,
+            true,
+            false,
+            true,
+            true,
+        )
+    }
+
+    /// Build every command that running a recipe would execute
+    fn commands(mold: &Mold, target: &Target) -> Vec<String> {
+        let task = mold.bind(target).and_then(|x| mold.build_task(&x)).unwrap();
+        task.commands
+            .iter()
+            .map(|(args, _)| args.join(" "))
+            .collect()
+    }
+
+    fn run(mold: &Mold, name: &str) -> Vec<String> {
+        commands(mold, &Target::new(name))
+    }
+
+    /// Build the arguments of every command that running a recipe would execute
+    fn args(mold: &Mold, name: &str) -> Vec<Vec<String>> {
+        let task = mold.build_task(&Target::new(name)).unwrap();
+        task.commands.into_iter().map(|(args, _)| args).collect()
+    }
+
+    fn target(name: &str, args: Vec<Arg>) -> Target {
+        Target {
+            name: name.into(),
+            args,
+        }
+    }
+
+    #[test]
+    fn scripts_run_through_their_runtime() {
+        let dir = scratch(
+            "script",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nruntime py \"python3 -u\" \"py\"\n\
+                 recipe named { script \"py\" \"print(1)\" }\n\
+                 recipe anonymous { script \"awk -f\" \"BEGIN {}\" }\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        let script = mold
+            .mold_dir
+            .join(format!("script-{}.py", util::hash_string("print(1)")));
+        let script = script.to_string_lossy();
+        assert_eq!(args(&mold, "named"), [vec!["python3", "-u", &script]]);
+        let script = mold
+            .mold_dir
+            .join(format!("script-{}.tmp", util::hash_string("BEGIN {}")));
+        let script = script.to_string_lossy();
+        assert_eq!(args(&mold, "anonymous"), [vec!["awk", "-f", &script]]);
+    }
+
+    #[test]
+    fn files_run_through_their_shebang_or_runtime() {
+        let dir = scratch(
+            "file",
+            &[
+                (
+                    "moldfile",
+                    "version \"0.7\"\nrecipe shebang { file \"build.sh\" }\n\
+                     recipe extension { file \"build.py\" }\nrecipe named { file \"build.py\" as \"perl\" }\n\
+                     recipe missing { file \"nope.sh\" }\n",
+                ),
+                ("build.sh", "#!/bin/sh -e\necho hi\n"),
+                ("build.py", "print(1)\n"),
+            ],
+        );
+        let mold = load(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        assert_eq!(
+            args(&mold, "shebang"),
+            [vec!["/bin/sh", "-e", &path("build.sh")]]
+        );
+        assert_eq!(
+            args(&mold, "extension"),
+            [vec!["python3", &path("build.py")]]
+        );
+        assert_eq!(args(&mold, "named"), [vec!["perl", &path("build.py")]]);
+        let err = mold.build_task(&Target::new("missing")).err().unwrap();
+        assert!(err.to_string().contains("Couldn't find script file"));
+    }
+
+    #[test]
+    fn conditions_on_computed_variables_are_computed_when_explaining() {
+        let code = "version \"0.7\"\nvar OS = $(echo linux)\n\
+                    if $OS == \"linux\" {\n  recipe r { $ \"echo yes\" }\n} else {\n  recipe r { $ \"echo no\" }\n}\n";
+        let dir = scratch("computed-condition", &[("moldfile", code)]);
+        assert_eq!(run(&load(&dir).unwrap(), "r"), ["echo yes"]);
+    }
+
+    #[test]
+    fn computed_variables_capture_command_output() {
+        let dir = scratch(
+            "computed",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nvar NAME = \"world\"\nvar GREETING = $(echo \"hello $NAME\")\n\
+                 recipe r { $ \"echo $GREETING\" }\n",
+            )],
+        );
+        let path = dir.join("moldfile");
+        let mold = Mold::init(&path, vec![], false, true, false, false, true).unwrap();
+        assert_eq!(args(&mold, "r"), [vec!["echo", "hello world"]]);
+
+        // dry runs show the command instead of running it
+        let mold = load(&dir).unwrap();
+        assert_eq!(args(&mold, "r"), [vec!["echo", "$(echo \"hello $NAME\")"]]);
+    }
+
+    #[test]
+    fn unset_variables_differ_from_empty_ones() {
+        let dir = scratch(
+            "unset",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nvar EMPTY = \"\"\nunset HOME\n\
+                 recipe empty { $ \"echo $EMPTY\" }\nrecipe unset { $ \"echo $HOME\" }\n",
+            )],
+        );
+        let path = dir.join("moldfile");
+        let mold = Mold::init(&path, vec![], false, true, true, true, true).unwrap();
+        assert_eq!(args(&mold, "empty"), [vec!["echo", ""]]);
+        let err = mold.build_task(&Target::new("unset")).err().unwrap();
+        assert!(err.to_string().contains("is not defined"));
+
+        // without strict mode, unset variables are empty instead of coming from the environment
+        assert_eq!(args(&load(&dir).unwrap(), "unset"), [vec!["echo", ""]]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unset_variables_are_removed_from_the_environment() {
+        let dir = scratch(
+            "unset-env",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nshell \"sh -c\"\nunset HOME\n\
+                 recipe r { $ \"test -z \\\"${HOME+x}\\\" && touch \\\"$MOLD_ROOT/unset\\\"\" }\n",
+            )],
+        );
+        load(&dir).unwrap().execute(&Target::new("r")).unwrap();
+        assert!(dir.join("unset").exists());
+    }
+
+    #[test]
+    fn recipe_variables_are_scoped_to_their_recipe() {
+        let dir = scratch(
+            "recipe-vars",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nvar A = \"global\"\n\
+                 recipe set {\n  var A = \"recipe\"\n  var B = \"$A-b\"\n  $ \"echo $A $B\"\n}\n\
+                 recipe default {\n  var A := \"recipe\"\n  var C := \"c\"\n  $ \"echo $A $C\"\n}\n\
+                 recipe arg(x) {\n  var B = \"$x-b\"\n  $ \"echo $B\"\n}\n\
+                 recipe plain { $ \"echo $A\" }\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        assert_eq!(run(&mold, "set"), ["echo recipe recipe-b"]);
+        assert_eq!(run(&mold, "default"), ["echo global c"]);
+        let target = target("arg", vec![Arg::Positional("a".into())]);
+        assert_eq!(commands(&mold, &target), ["echo a-b"]);
+        assert_eq!(run(&mold, "plain"), ["echo global"]);
+    }
+
+    #[test]
+    fn aliases_resolve_to_their_recipe() {
+        let dir = scratch(
+            "alias",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nalias b = build\nrecipe build(x = \"1\") { $ \"echo $x\" }\n\
+                 recipe all { require b(\"2\") }\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        assert!(mold.has_recipe("b"));
+        assert_eq!(mold.resolve("b"), "build");
+        assert_eq!(run(&mold, "b"), ["echo 1"]);
+
+        let targets = mold
+            .parse_targets(&["b".into(), "3".into()], false)
+            .unwrap();
+        assert_eq!(commands(&mold, &targets[0]), ["echo 3"]);
+
+        let found = mold.find_all_dependencies(&[Target::new("all")]).unwrap();
+        let found: Vec<_> = found.iter().map(|x| x.to_string()).collect();
+        assert_eq!(found, ["build(x=2)", "all"]);
+    }
+
+    #[test]
+    fn private_recipes_need_all_to_run_directly() {
+        let dir = scratch(
+            "private",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nprivate recipe hidden { $ \"echo hidden\" }\n\
+                 recipe _underscore { $ \"echo underscore\" }\nrecipe shown { require hidden }\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        assert!(mold.recipes["hidden"].private);
+        assert!(mold.recipes["_underscore"].private);
+        assert!(!mold.recipes["shown"].private);
+
+        for name in &["hidden", "_underscore"] {
+            let err = mold
+                .parse_targets(&[name.to_string()], false)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("is private"));
+            assert!(mold.parse_targets(&[name.to_string()], true).is_ok());
+        }
+
+        let targets = mold.parse_targets(&["shown".into()], false).unwrap();
+        assert_eq!(mold.find_all_dependencies(&targets).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn groups_prefix_their_recipes() {
+        let dir = scratch(
+            "group",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nrecipe setup { $ \"echo setup\" }\nrecipe push { $ \"echo top\" }\n\
+                 group docker {\n  alias b = build\n  recipe push { $ \"echo push\" }\n\
+                 recipe build {\n    require setup\n    require push\n    $ \"echo build\"\n  }\n\
+                 group inner { recipe x { require push } }\n}\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        let requires = |name: &str| -> Vec<String> {
+            mold.recipes[name]
+                .requires
+                .iter()
+                .map(|x| x.name.clone())
+                .collect()
+        };
+        assert_eq!(requires("docker/build"), ["setup", "docker/push"]);
+        assert_eq!(requires("docker/inner/x"), ["docker/push"]);
+        assert_eq!(mold.aliases["docker/b"], "docker/build");
+    }
+
+    #[test]
+    fn only_the_root_moldfile_picks_default_targets() {
+        let dir = scratch(
+            "default",
+            &[
+                (
+                    "moldfile",
+                    "version \"0.7\"\ninclude \"./other\"\ndefault [a, b(\"x\")]\n\
+                     recipe a { $ \"echo a\" }\nrecipe b(v) { $ \"echo $v\" }\n",
+                ),
+                (
+                    "other",
+                    "version \"0.7\"\ndefault c\nrecipe c { $ \"echo c\" }\n",
+                ),
+            ],
+        );
+        let mold = load(&dir).unwrap();
+        let b = target("b", vec![Arg::Positional("x".into())]);
+        assert_eq!(mold.default_targets, [Target::new("a"), b]);
+    }
+
+    #[test]
+    fn plain_recipes_cant_share_a_name() {
+        let dir = scratch(
+            "plain",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nrecipe a { $ \"echo 1\" }\nrecipe a { $ \"echo 2\" }\n",
+            )],
+        );
+        let err = load(&dir).err().unwrap().to_string();
+        assert!(err.contains("is already defined"), "{}", err);
+    }
+
+    #[test]
+    fn override_replaces_a_recipe() {
+        let dir = scratch(
+            "override",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nrecipe a { $ \"echo 1\" }\noverride recipe a { $ \"echo 2\" }\n",
+            )],
+        );
+        assert_eq!(run(&load(&dir).unwrap(), "a"), ["echo 2"]);
+    }
+
+    #[test]
+    fn extend_puts_the_base_commands_at_super() {
+        let dir = scratch(
+            "extend",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nrecipe a { $ \"echo base\" }\n\
+                 extend recipe a {\n  $ \"echo before\"\n  super\n  $ \"echo after\"\n}\n",
+            )],
+        );
+        assert_eq!(
+            run(&load(&dir).unwrap(), "a"),
+            ["echo before", "echo base", "echo after"]
+        );
+    }
+
+    #[test]
+    fn extensions_can_come_before_their_base() {
+        let dir = scratch(
+            "extend-first",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nextend recipe a { $ \"echo ext\" }\nrecipe a { $ \"echo base\" }\n",
+            )],
+        );
+        assert_eq!(run(&load(&dir).unwrap(), "a"), ["echo base", "echo ext"]);
+    }
+
+    #[test]
+    fn importing_a_module_twice_keeps_its_recipes_once() {
+        let dir = scratch("reimport", &[]);
+        let m = dir.join("m").display().to_string();
+        let files = [
+            (
+                "m",
+                "version \"0.7\"\nrecipe build { $ \"echo m\" }\n".to_string(),
+            ),
+            ("x", format!("version \"0.7\"\nimport \"{}\" as m/\n", m)),
+            ("y", format!("version \"0.7\"\nimport \"{}\" as m/\n", m)),
+        ];
+        for (module, code) in &files {
+            let module = dir.join(module);
+            fs::create_dir_all(&module).unwrap();
+            fs::write(module.join("moldfile"), code).unwrap();
+            commit(&module);
+        }
+        let top = format!(
+            "version \"0.7\"\nimport \"{0}/x\"\nimport \"{0}/y\"\n",
+            dir.display()
+        );
+        fs::write(dir.join("moldfile"), top).unwrap();
+
+        let mold = load(&dir).unwrap();
+        assert_eq!(run(&mold, "m/build"), ["echo m"]);
+    }
+
+    /// Make a module that includes another moldfile, then import it with some selections
+    fn select(name: &str, imports: &[&str]) -> Mold {
+        let dir = scratch(
+            name,
+            &[
+                (
+                    "m/moldfile",
+                    "version \"0.7\"\ninclude \"./sub\" as sub/\nalias b = build\n\
+                     recipe build {\n  require sub/thing\n  $ \"echo build\"\n}\n\
+                     recipe test { $ \"echo test\" }\nrecipe publish { $ \"echo publish\" }\n",
+                ),
+                (
+                    "m/sub",
+                    "version \"0.7\"\nrecipe thing { $ \"echo thing\" }\n\
+                     recipe other { $ \"echo other\" }\n",
+                ),
+            ],
+        );
+        commit(&dir.join("m"));
+        let mut top = "version \"0.7\"\n".to_string();
+        for import in imports {
+            top += &format!("import \"{}\" {}\n", dir.join("m").display(), import);
+        }
+        fs::write(dir.join("moldfile"), top).unwrap();
+        load(&dir).unwrap()
+    }
+
+    #[test]
+    fn only_keeps_required_recipes_private() {
+        let mold = select("only", &["as m/ only [build]"]);
+        assert!(!mold.recipes["m/build"].private);
+        assert!(mold.recipes["m/sub/thing"].private);
+        assert!(!mold.recipes.contains_key("m/test"));
+        assert!(!mold.recipes.contains_key("m/sub/other"));
+        assert_eq!(mold.aliases["m/b"], "m/build");
+    }
+
+    #[test]
+    fn only_applies_to_included_recipes_and_aliases() {
+        let mold = select("only-included", &["as m/ only [sub/other, b]"]);
+        assert!(mold.recipes.contains_key("m/sub/other"));
+        assert!(!mold.recipes["m/build"].private);
+        assert_eq!(mold.aliases["m/b"], "m/build");
+        assert!(!mold.recipes.contains_key("m/publish"));
+    }
+
+    #[test]
+    fn except_drops_included_recipes() {
+        let mold = select("except", &["as e/ except [publish, sub/other]"]);
+        assert!(mold.recipes.contains_key("e/test"));
+        assert!(!mold.recipes["e/sub/thing"].private);
+        assert!(!mold.recipes.contains_key("e/publish"));
+        assert!(!mold.recipes.contains_key("e/sub/other"));
+    }
+
+    #[test]
+    fn except_keeps_required_recipes() {
+        let mold = select("except-required", &["as e/ except [sub/thing]"]);
+        assert!(mold.recipes["e/sub/thing"].private);
+    }
+
+    #[test]
+    fn includes_are_loaded_once_per_prefix() {
+        let dir = scratch(
+            "include",
+            &[
+                (
+                    "moldfile",
+                    "version \"0.7\"\ninclude \"./mod\" as a/\ninclude \"./mod\" as b/\n\
+                     include \"./mod\" as a/\n",
+                ),
+                ("mod", "version \"0.7\"\nrecipe r { $ \"echo r\" }\n"),
+            ],
+        );
+        let mold = load(&dir).unwrap();
+        assert_eq!(run(&mold, "a/r"), ["echo r"]);
+        assert_eq!(run(&mold, "b/r"), ["echo r"]);
+    }
+
+    #[test]
+    fn include_cycles_stop_at_the_moldfile_being_loaded() {
+        let dir = scratch(
+            "include-cycle",
+            &[
+                (
+                    "moldfile",
+                    "version \"0.7\"\ninclude \"./y\" as p/\nrecipe x { $ \"echo x\" }\n",
+                ),
+                (
+                    "y",
+                    "version \"0.7\"\ninclude \"./moldfile\" as q/\nrecipe y { $ \"echo y\" }\n",
+                ),
+            ],
+        );
+        let mold = load(&dir).unwrap();
+        assert!(mold.recipes.contains_key("x"));
+        assert!(mold.recipes.contains_key("p/y"));
+        assert!(!mold.recipes.contains_key("p/q/x"));
+    }
+
+    /// Import a module that builds a variable out of another one it takes a default for
+    fn import_with(name: &str, imports: &[&str]) -> Mold {
+        let dir = scratch(
+            name,
+            &[(
+                "m/moldfile",
+                "version \"0.7\"\nvar TARGET := \"none\"\nvar OUT = \"built-$TARGET\"\n\
+                 recipe show { $ \"echo $OUT\" }\n",
+            )],
+        );
+        commit(&dir.join("m"));
+        let mut top = "version \"0.7\"\n".to_string();
+        for import in imports {
+            top += &format!("import \"{}\" {}\n", dir.join("m").display(), import);
+        }
+        fs::write(dir.join("moldfile"), top).unwrap();
+        load(&dir).unwrap()
+    }
+
+    #[test]
+    fn with_passes_variables_to_one_import() {
+        let mold = import_with(
+            "with",
+            &[
+                "as a/ with { TARGET = \"a\" }",
+                "as b/ with { TARGET = \"b\" }",
+            ],
+        );
+        assert_eq!(run(&mold, "a/show"), ["echo built-a"]);
+        assert_eq!(run(&mold, "b/show"), ["echo built-b"]);
+        assert!(!mold.vars.contains_key("OUT"));
+    }
+
+    #[test]
+    fn empty_with_still_scopes_an_import() {
+        let mold = import_with("with-empty", &["as a/ with {}"]);
+        assert_eq!(run(&mold, "a/show"), ["echo built-none"]);
+        assert!(!mold.vars.contains_key("OUT"));
+        assert!(!mold.vars.contains_key("TARGET"));
+    }
+
+    fn greet(name: &str) -> Mold {
+        let dir = scratch(
+            name,
+            &[(
+                "moldfile",
+                "version \"0.7\"\nvar WHO = \"there\"\n\
+                 recipe greet(name, greeting = \"hi $WHO\") { $ \"echo $greeting $name\" }\n",
+            )],
+        );
+        load(&dir).unwrap()
+    }
+
+    #[test]
+    fn arguments_are_bound_literally() {
+        let mold = greet("args-literal");
+        let args = vec![Arg::Positional("pa$$word".into())];
+        assert_eq!(
+            commands(&mold, &target("greet", args)),
+            ["echo hi there pa$$word"]
+        );
+    }
+
+    #[test]
+    fn arguments_can_be_named() {
+        let mold = greet("args-named");
+        let args = vec![
+            Arg::Named("greeting".into(), "yo".into()),
+            Arg::Positional("bob".into()),
+        ];
+        assert_eq!(commands(&mold, &target("greet", args)), ["echo yo bob"]);
+    }
+
+    #[test]
+    fn arguments_must_match_the_parameters() {
+        let mold = greet("args-invalid");
+        let bind = |args| mold.bind(&target("greet", args)).err().unwrap().to_string();
+        assert!(bind(vec![]).contains("is missing required argument"));
+        assert!(bind(vec![Arg::Named("who".into(), "x".into())]).contains("has no parameter"));
+        let args = vec!["a", "b", "c"];
+        let args = args
+            .into_iter()
+            .map(|x| Arg::Positional(x.into()))
+            .collect();
+        assert!(bind(args).contains("takes 2 argument(s)"));
+        let args = vec![
+            Arg::Positional("a".into()),
+            Arg::Named("name".into(), "b".into()),
+        ];
+        assert!(bind(args).contains("more than once"));
+    }
+
+    #[test]
+    fn splat_finds_the_variable_name() {
+        assert_eq!(splat("$@FILES"), Some("FILES"));
+        assert_eq!(splat("$@{FILES}"), Some("FILES"));
+        assert_eq!(splat("$FILES"), None);
+        assert_eq!(splat("x$@FILES"), None);
+    }
+
+    #[test]
+    fn variables_expand_to_one_argument_unless_splatted() {
+        let dir = scratch(
+            "splat",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nvar FILES = \"a b 'c d'\"\n\
+                 recipe one { $ \"ls $FILES\" }\nrecipe many { $ \"ls $@FILES -l\" }\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        assert_eq!(args(&mold, "one"), [vec!["ls", "a b 'c d'"]]);
+        assert_eq!(args(&mold, "many"), [vec!["ls", "a", "b", "c d", "-l"]]);
+    }
+
+    #[test]
+    fn shell_lines_only_expand_splats_and_dollars() {
+        let dir = scratch(
+            "shell",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nshell \"sh -c\"\nvar FILES = \"a 'c d'\"\n\
+                 recipe r { $ \"for x in $@FILES; do echo $x $$; done\" }\n\
+                 recipe bash {\n  shell \"bash -c\"\n  $ \"echo $FILES\"\n}\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        let line = "for x in a 'c d'; do echo $x $; done";
+        assert_eq!(args(&mold, "r"), [vec!["sh", "-c", line]]);
+        assert_eq!(args(&mold, "bash"), [vec!["bash", "-c", "echo $FILES"]]);
+    }
+
+    #[test]
+    fn extended_lines_keep_the_shell_of_their_moldfile() {
+        let dir = scratch(
+            "shell-extend",
+            &[
+                (
+                    "moldfile",
+                    "version \"0.7\"\nshell \"sh -c\"\ninclude \"./base\"\n\
+                     extend recipe r {\n  super\n  $ \"echo ext\"\n}\n",
+                ),
+                (
+                    "base",
+                    "version \"0.7\"\nrecipe r {\n  before \"echo hook\"\n  $ \"echo base\"\n}\n",
+                ),
+            ],
+        );
+        let mold = load(&dir).unwrap();
+        assert_eq!(
+            args(&mold, "r"),
+            [
+                vec!["echo", "hook"],
+                vec!["echo", "base"],
+                vec!["sh", "-c", "echo ext"]
+            ]
+        );
+    }
+
+    #[test]
+    fn hooks_at_the_top_of_a_moldfile_surround_each_recipe() {
+        let dir = scratch(
+            "hooks",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nbefore \"echo outer-before\"\nafter \"echo outer-after\"\n\
+                 recipe r {\n  before \"echo before\"\n  after \"echo after\"\n  $ \"echo r\"\n}\n",
+            )],
+        );
+        assert_eq!(
+            run(&load(&dir).unwrap(), "r"),
+            [
+                "echo outer-before",
+                "echo before",
+                "echo r",
+                "echo after",
+                "echo outer-after"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finally_hooks_see_how_the_recipe_went() {
+        let dir = scratch(
+            "finally",
+            &[(
+                "moldfile",
+                "version \"0.7\"\n\
+                 recipe fails {\n  finally \"touch $MOLD_ROOT/fails-$MOLD_STATUS\"\n  $ \"false\"\n}\n\
+                 recipe broken {\n  finally \"touch $MOLD_ROOT/broken-$MOLD_STATUS\"\n  $ \"echo 'x\"\n}\n\
+                 recipe works {\n  finally \"touch $MOLD_ROOT/works-$MOLD_STATUS\"\n  $ \"true\"\n}\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        assert!(mold.execute(&Target::new("fails")).is_err());
+        assert!(mold.execute(&Target::new("broken")).is_err());
+        assert!(mold.execute(&Target::new("works")).is_ok());
+        assert!(dir.join("fails-1").exists());
+        assert!(dir.join("broken-1").exists());
+        assert!(dir.join("works-0").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tolerated_failures_dont_stop_a_recipe() {
+        let dir = scratch(
+            "tolerant",
+            &[(
+                "moldfile",
+                "version \"0.7\"\nrecipe dash {\n  -$ \"false\"\n  $ \"touch $MOLD_ROOT/dash\"\n}\n\
+                 recipe ignore {\n  ignore_errors\n  $ \"false\"\n  $ \"touch $MOLD_ROOT/ignore\"\n}\n\
+                 recipe allowed {\n  allowed_exit [1]\n  $ \"false\"\n  $ \"touch $MOLD_ROOT/allowed\"\n}\n\
+                 recipe strict {\n  $ \"false\"\n  $ \"touch $MOLD_ROOT/strict\"\n}\n",
+            )],
+        );
+        let mold = load(&dir).unwrap();
+        for name in &["dash", "ignore", "allowed"] {
+            assert!(mold.execute(&Target::new(name)).is_ok());
+            assert!(dir.join(name).exists());
+        }
+        assert!(mold.execute(&Target::new("strict")).is_err());
+        assert!(!dir.join("strict").exists());
+    }
+}
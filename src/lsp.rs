@@ -0,0 +1,852 @@
+use super::lang;
+use super::lang::collect_atoms;
+use super::lang::find_var;
+use super::lang::Expr;
+use super::lang::Rule;
+use super::lang::Source;
+use super::lang::Span;
+use super::lang::Statement;
+use super::lang::StatementKind;
+use super::Mold;
+use super::NameSet;
+use super::Selection;
+use super::Target;
+use super::VarMap;
+use failure::Error;
+use indexmap::IndexMap;
+use lsp_types::CompletionItem;
+use lsp_types::CompletionItemKind;
+use lsp_types::Diagnostic;
+use lsp_types::DiagnosticSeverity;
+use lsp_types::Hover;
+use lsp_types::HoverContents;
+use lsp_types::Location;
+use lsp_types::MarkupContent;
+use lsp_types::MarkupKind;
+use lsp_types::Position;
+use lsp_types::Range;
+use lsp_types::Url;
+use regex::Regex;
+use serde_json::json;
+use serde_json::Value;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+use std::path::PathBuf;
+
+// JSON-RPC error codes that are needed here
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+// predicates that can be used in conditions alongside env atoms
+const PREDICATES: &[&str] = &["defined", "exists", "is_dir", "has_cmd", "env"];
+
+/// Run a language server over stdin and stdout until the client asks it to exit
+///
+/// Moldfiles are loaded with the given envs activated, but remotes that haven't been cloned yet
+/// are never cloned, since that would be far too slow to do while editing.
+pub fn run(envs: Vec<String>) -> Result<(), Error> {
+    // nothing can be printed to stdout except for LSP messages, so make sure that errors don't
+    // contain any escape codes either
+    colored::control::set_override(false);
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut input = stdin.lock();
+    let mut output = stdout.lock();
+
+    let mut server = Server {
+        envs,
+        documents: IndexMap::new(),
+        molds: IndexMap::new(),
+    };
+
+    while let Some(mut message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or("").to_string();
+        let id = message["id"].take();
+
+        if method == "exit" {
+            break;
+        }
+
+        let result = server.handle(&method, message["params"].take(), &mut output);
+
+        // notifications don't get a response, even when they fail
+        if id.is_null() {
+            continue;
+        }
+
+        let response = match result {
+            Ok(Some(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Ok(None) => error_response(id, METHOD_NOT_FOUND, format!("Unknown method {}", method)),
+            Err(err) => error_response(id, INTERNAL_ERROR, err.to_string()),
+        };
+
+        write_message(&mut output, &response)?;
+    }
+
+    Ok(())
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message},
+    })
+}
+
+/// Read a single message, returning None when the input has been closed
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Value>, Error> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| failure::format_err!("Message has no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<(), Error> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn uri_to_path(uri: &Url) -> Result<PathBuf, Error> {
+    uri.to_file_path()
+        .map_err(|_| failure::format_err!("{} is not a file", uri))
+}
+
+/// Convert an LSP position, which counts UTF-16 code units, into a byte offset
+fn offset(code: &str, position: Position) -> usize {
+    let start = code
+        .split_inclusive('\n')
+        .take(position.line as usize)
+        .map(str::len)
+        .sum::<usize>();
+
+    let mut units = 0;
+    for (idx, ch) in code[start..].char_indices() {
+        if units >= position.character as usize || ch == '\n' {
+            return start + idx;
+        }
+        units += ch.len_utf16();
+    }
+
+    code.len()
+}
+
+/// Convert a byte offset into an LSP position
+fn position(code: &str, offset: usize) -> Position {
+    let before = &code[..offset.min(code.len())];
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+fn range(code: &str, start: usize, end: usize) -> Range {
+    Range {
+        start: position(code, start),
+        end: position(code, end),
+    }
+}
+
+/// Convert an error into a diagnostic, pointing at its location if it has one
+fn diagnostic(code: &str, err: &Error) -> Diagnostic {
+    let (start, end, message) = match err.downcast_ref::<pest::error::Error<Rule>>() {
+        Some(err) => {
+            let (start, end) = match err.location {
+                pest::error::InputLocation::Pos(pos) => (pos, pos),
+                pest::error::InputLocation::Span(span) => span,
+            };
+            (start, end, err.variant.message().to_string())
+        }
+        None => (0, 0, err.to_string()),
+    };
+
+    Diagnostic {
+        range: range(code, start, end),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("mold".into()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+fn completion(label: &str, kind: CompletionItemKind, detail: Option<&str>) -> CompletionItem {
+    CompletionItem {
+        label: label.into(),
+        kind: Some(kind),
+        detail: detail.map(String::from),
+        ..CompletionItem::default()
+    }
+}
+
+/// Whether the name starting at a byte offset is a variable reference, like `$NAME`, `${NAME}`
+/// or a splat like `$@NAME`
+fn is_var_ref(code: &str, start: usize) -> bool {
+    let before = &code[..start];
+    let before = before.strip_suffix('{').unwrap_or(before);
+    let before = before.strip_suffix('@').unwrap_or(before);
+    before.ends_with('$')
+}
+
+/// Find the name under a byte offset
+fn word_at(code: &str, offset: usize) -> Option<(usize, &str)> {
+    let is_word = |ch: char| ch.is_ascii_alphanumeric() || "_-/:".contains(ch);
+    let start = code[..offset]
+        .char_indices()
+        .rev()
+        .find(|(_, ch)| !is_word(*ch))
+        .map_or(0, |(idx, ch)| idx + ch.len_utf8());
+    let end = code[offset..]
+        .find(|x| !is_word(x))
+        .map_or(code.len(), |x| offset + x);
+
+    if start == end {
+        None
+    } else {
+        Some((start, &code[start..end]))
+    }
+}
+
+/// Find the innermost recipe containing a byte offset
+fn recipe_at(stmts: &[Statement], offset: usize) -> Option<&Statement> {
+    let mut found = None;
+    lang::visit(stmts, &mut |stmt| {
+        let inside = stmt.span.start <= offset && offset <= stmt.span.end;
+        if let (StatementKind::Recipe(..), true) = (&stmt.kind, inside) {
+            found = Some(stmt);
+        }
+    });
+
+    found
+}
+
+/// Find the prefix that the groups containing a byte offset give to the names inside them
+fn group_at(stmts: &[Statement], offset: usize) -> String {
+    for stmt in stmts {
+        if offset < stmt.span.start || stmt.span.end < offset {
+            continue;
+        }
+
+        match &stmt.kind {
+            StatementKind::Group(name, body) => {
+                return format!("{}/{}", name, group_at(body, offset))
+            }
+            StatementKind::IfBlock(body)
+            | StatementKind::If(_, body)
+            | StatementKind::Else(body) => return group_at(body, offset),
+            _ => {}
+        }
+    }
+
+    String::new()
+}
+
+/// Find the recipe that a name at a byte offset refers to
+///
+/// Names inside a group refer to that group's recipes first, and aliases are resolved.
+fn recipe_name(mold: &Mold, stmts: &[Statement], offset: usize, name: &str) -> String {
+    let owner = format!("{}{}", group_at(stmts, offset), name);
+    let defined: NameSet = mold
+        .recipes
+        .keys()
+        .chain(mold.aliases.keys())
+        .cloned()
+        .collect();
+    let name = lang::relative(&owner, name, &defined);
+    mold.resolve(&name).to_string()
+}
+
+/// Find where a recipe is defined in a body, given the prefix of the file or group it's in
+fn find_recipe(stmts: &[Statement], prefix: &str, name: &str) -> Option<Span> {
+    for stmt in stmts {
+        let found = match &stmt.kind {
+            StatementKind::Recipe(rec_name, ..) if format!("{}{}", prefix, rec_name) == name => {
+                Some(stmt.span)
+            }
+            StatementKind::Group(group, body) => {
+                find_recipe(body, &format!("{}{}/", prefix, group), name)
+            }
+            StatementKind::IfBlock(body)
+            | StatementKind::If(_, body)
+            | StatementKind::Else(body) => find_recipe(body, prefix, name),
+            _ => None,
+        };
+
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+/// Describe the commands a recipe executes when it's run without arguments, the same way
+/// `--explain` does
+///
+/// This is only safe because the server's Molds are dry, so computed variables aren't executed
+/// and scripts aren't written. Recipes with required parameters can't be described.
+fn command_lines(mold: &Mold, name: &str) -> Vec<String> {
+    let task = mold
+        .bind(&Target::new(name))
+        .and_then(|target| mold.build_task(&target));
+
+    let task = match task {
+        Ok(task) => task,
+        Err(_) => return vec![],
+    };
+
+    task.commands
+        .iter()
+        .map(|(args, tolerant)| {
+            let prompt = if *tolerant { "-$" } else { "$" };
+            format!("{} {}", prompt, shell_words::join(args))
+        })
+        .collect()
+}
+
+struct Server {
+    /// Environments to activate when loading moldfiles
+    envs: Vec<String>,
+
+    /// The code of every open document, by URI
+    documents: IndexMap<Url, String>,
+
+    /// The most recent successfully loaded Mold for every open document, by URI
+    molds: IndexMap<Url, Mold>,
+}
+
+impl Server {
+    /// Handle a single request or notification, returning None if the method isn't supported
+    fn handle<W: Write>(
+        &mut self,
+        method: &str,
+        params: Value,
+        output: &mut W,
+    ) -> Result<Option<Value>, Error> {
+        let result = match method {
+            "initialize" => serde_json::to_value(lsp_types::InitializeResult {
+                capabilities: lsp_types::ServerCapabilities {
+                    text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Kind(
+                        lsp_types::TextDocumentSyncKind::FULL,
+                    )),
+                    hover_provider: Some(lsp_types::HoverProviderCapability::Simple(true)),
+                    definition_provider: Some(lsp_types::OneOf::Left(true)),
+                    completion_provider: Some(lsp_types::CompletionOptions {
+                        trigger_characters: Some(vec!["$".into(), "{".into(), " ".into()]),
+                        ..lsp_types::CompletionOptions::default()
+                    }),
+                    ..lsp_types::ServerCapabilities::default()
+                },
+                server_info: Some(lsp_types::ServerInfo {
+                    name: "mold".into(),
+                    version: Some(clap::crate_version!().into()),
+                }),
+            })?,
+
+            "initialized" | "shutdown" => Value::Null,
+
+            "textDocument/didOpen" => {
+                let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                self.publish(&uri, output)?;
+                Value::Null
+            }
+
+            "textDocument/didChange" => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(params)?;
+                let uri = params.text_document.uri;
+
+                // only full document sync is supported, so the last change is the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                self.publish(&uri, output)?;
+                Value::Null
+            }
+
+            "textDocument/didClose" => {
+                let params: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(params)?;
+                let uri = params.text_document.uri;
+                self.documents.shift_remove(&uri);
+                self.molds.shift_remove(&uri);
+                notify(
+                    output,
+                    "textDocument/publishDiagnostics",
+                    lsp_types::PublishDiagnosticsParams::new(uri, vec![], None),
+                )?;
+                Value::Null
+            }
+
+            "textDocument/completion" => {
+                let params: lsp_types::CompletionParams = serde_json::from_value(params)?;
+                let at = params.text_document_position;
+                serde_json::to_value(self.completion(&at.text_document.uri, at.position)?)?
+            }
+
+            "textDocument/hover" => {
+                let params: lsp_types::HoverParams = serde_json::from_value(params)?;
+                let at = params.text_document_position_params;
+                serde_json::to_value(self.hover(&at.text_document.uri, at.position)?)?
+            }
+
+            "textDocument/definition" => {
+                let params: lsp_types::GotoDefinitionParams = serde_json::from_value(params)?;
+                let at = params.text_document_position_params;
+                serde_json::to_value(self.definition(&at.text_document.uri, at.position)?)?
+            }
+
+            _ => return Ok(None),
+        };
+
+        Ok(Some(result))
+    }
+
+    fn document(&self, uri: &Url) -> Result<&str, Error> {
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or_else(|| failure::format_err!("Document {} isn't open", uri))
+    }
+
+    /// Create an empty Mold for a moldfile that won't clone, execute or write anything
+    fn new_mold(&self, path: &Path) -> Result<Mold, Error> {
+        let mut mold = Mold::new(path, self.envs.clone(), false, true, false)?;
+        mold.fetch = false;
+        mold.dry = true;
+        mold.compute_conditions = false;
+        Ok(mold)
+    }
+
+    /// Load a document and send its diagnostics to the client
+    fn publish<W: Write>(&mut self, uri: &Url, output: &mut W) -> Result<(), Error> {
+        let path = uri_to_path(uri)?;
+        let code = self.document(uri)?.to_string();
+        let source = Source {
+            code: &code,
+            path: &path,
+        };
+
+        // the document is compiled on its own first so that its errors keep their location,
+        // and then loaded along with its imports
+        let loaded = self
+            .new_mold(&path)
+            .and_then(|mut mold| lang::compile(&source, &mut mold, VarMap::new()).map(|_| mold))
+            .and_then(|_| self.new_mold(&path))
+            .and_then(|mut mold| {
+                mold.load(&source, "", false, &Selection::All, None)
+                    .map(|_| mold)
+            });
+
+        let diagnostics = match loaded {
+            Ok(mold) => {
+                self.molds.insert(uri.clone(), mold);
+                vec![]
+            }
+            Err(err) => vec![diagnostic(&code, &err)],
+        };
+
+        notify(
+            output,
+            "textDocument/publishDiagnostics",
+            lsp_types::PublishDiagnosticsParams::new(uri.clone(), diagnostics, None),
+        )
+    }
+
+    /// Parse a document, ignoring syntax errors since they're reported as diagnostics
+    fn statements(&self, uri: &Url) -> Vec<Statement> {
+        let path = uri_to_path(uri).unwrap_or_default();
+        let code = self.document(uri).unwrap_or("");
+        lang::parse(&Source { code, path: &path }).unwrap_or_default()
+    }
+
+    /// Every moldfile loaded for a document, parsed
+    fn files(&self, uri: &Url) -> Vec<(PathBuf, String, String, Vec<Statement>)> {
+        let path = uri_to_path(uri).unwrap_or_default();
+        let loaded = match self.molds.get(uri) {
+            Some(mold) => mold.files.clone(),
+            None => vec![(path.clone(), String::new())],
+        };
+
+        loaded
+            .into_iter()
+            .filter_map(|(file, prefix)| {
+                // unsaved changes take priority over what's on disk
+                let code = if file == path {
+                    self.document(uri).ok()?.to_string()
+                } else {
+                    fs::read_to_string(&file).ok()?
+                };
+                let stmts = lang::parse(&Source {
+                    code: &code,
+                    path: &file,
+                })
+                .ok()?;
+                Some((file, prefix, code, stmts))
+            })
+            .collect()
+    }
+
+    fn completion(&self, uri: &Url, pos: Position) -> Result<Vec<CompletionItem>, Error> {
+        let code = self.document(uri)?;
+        let offset = offset(code, pos);
+        let line = &code[code[..offset].rfind('\n').map_or(0, |x| x + 1)..offset];
+        let stmts = self.statements(uri);
+        let mold = self.molds.get(uri);
+
+        let mut items = vec![];
+
+        if Regex::new(r"\$@?\{?[A-Za-z0-9_]*$")?.is_match(line) {
+            let mut names = NameSet::new();
+            names.insert("MOLD_SOURCE".into());
+            names.insert("MOLD_STATUS".into());
+
+            match mold {
+                Some(mold) => names.extend(mold.vars.keys().cloned()),
+                None => lang::visit(&stmts, &mut |stmt| {
+                    if let StatementKind::Var(name, _) | StatementKind::Default(name, _) =
+                        &stmt.kind
+                    {
+                        names.insert(name.clone());
+                    }
+                }),
+            }
+
+            if let Some(StatementKind::Recipe(_, params, body, ..)) =
+                recipe_at(&stmts, offset).map(|x| &x.kind)
+            {
+                names.extend(params.iter().map(|param| param.name.clone()));
+                lang::visit(body, &mut |stmt| {
+                    if let StatementKind::Var(name, _) | StatementKind::Default(name, _) =
+                        &stmt.kind
+                    {
+                        names.insert(name.clone());
+                    }
+                });
+            }
+
+            items.extend(
+                names
+                    .iter()
+                    .map(|x| completion(x, CompletionItemKind::VARIABLE, None)),
+            );
+        } else if Regex::new(
+            r"^\s*(require\s+|default\s+|default\s*\[[^\]]*|(override|extend)\s+recipe\s+)[A-Za-z0-9_/:-]*$",
+        )?
+        .is_match(line)
+        {
+            match mold {
+                Some(mold) => {
+                    for (name, recipe) in &mold.recipes {
+                        let detail = recipe.help.as_deref();
+                        items.push(completion(name, CompletionItemKind::FUNCTION, detail));
+                    }
+
+                    for (alias, name) in &mold.aliases {
+                        items.push(completion(alias, CompletionItemKind::FUNCTION, Some(name)));
+                    }
+                }
+                None => lang::visit(&stmts, &mut |stmt| {
+                    if let StatementKind::Recipe(name, ..) = &stmt.kind {
+                        items.push(completion(name, CompletionItemKind::FUNCTION, None));
+                    }
+                }),
+            }
+        } else if Regex::new(r"\b(if|elif)\s[^{}]*$")?.is_match(line) {
+            let mut atoms = NameSet::new();
+            atoms.extend(self.envs.iter().cloned());
+
+            for (_, _, _, file_stmts) in self.files(uri) {
+                lang::visit(&file_stmts, &mut |stmt| {
+                    if let StatementKind::If(expr, _) = &stmt.kind {
+                        let mut found = vec![];
+                        collect_atoms(expr, &mut found);
+                        for atom in found {
+                            if let Expr::Atom(name) = atom {
+                                atoms.insert(name.clone());
+                            }
+                        }
+                    }
+                });
+            }
+
+            items.extend(
+                atoms
+                    .iter()
+                    .map(|x| completion(x, CompletionItemKind::CONSTANT, None)),
+            );
+            items.extend(
+                PREDICATES
+                    .iter()
+                    .map(|x| completion(x, CompletionItemKind::FUNCTION, None)),
+            );
+        }
+
+        Ok(items)
+    }
+
+    fn hover(&self, uri: &Url, pos: Position) -> Result<Option<Hover>, Error> {
+        let code = self.document(uri)?;
+        let offset = offset(code, pos);
+        let mold = match self.molds.get(uri) {
+            Some(mold) => mold,
+            None => return Ok(None),
+        };
+
+        let (start, word) = match word_at(code, offset) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let name = recipe_name(mold, &self.statements(uri), offset, word);
+        let text = if is_var_ref(code, start) {
+            match mold.vars.get(word) {
+                Some(var) => format!(
+                    "```\n${} = {}\n```\n\nfrom {}",
+                    word,
+                    var,
+                    mold.var_origin(word)
+                ),
+                None => return Ok(None),
+            }
+        } else if let Some(recipe) = mold.recipes.get(&name) {
+            let mut text = format!("```\n{}\n```", recipe.signature(&name));
+
+            if let Some(help) = &recipe.help {
+                text.push_str(&format!("\n\n{}", help));
+            }
+
+            if !recipe.requires.is_empty() {
+                let deps: Vec<_> = recipe.requires.iter().map(|x| x.to_string()).collect();
+                text.push_str(&format!("\n\ndepends on: `{}`", deps.join(" ")));
+            }
+
+            let commands = command_lines(mold, &name);
+            if !commands.is_empty() {
+                text.push_str(&format!("\n\n```sh\n{}\n```", commands.join("\n")));
+            }
+
+            text
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: Some(range(code, start, start + word.len())),
+        }))
+    }
+
+    fn definition(&self, uri: &Url, pos: Position) -> Result<Option<Location>, Error> {
+        let code = self.document(uri)?;
+        let offset = offset(code, pos);
+
+        let (start, word) = match word_at(code, offset) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let files = self.files(uri);
+        let location = |path: &Path, code: &str, span: Span| {
+            // only point at the first line, rather than a recipe's entire body
+            let end = code[span.start..span.end]
+                .find('\n')
+                .map_or(span.end, |x| span.start + x);
+            Url::from_file_path(path).ok().map(|uri| Location {
+                uri,
+                range: range(code, span.start, end),
+            })
+        };
+
+        if is_var_ref(code, start) {
+            // recipe variables and parameters take priority over globals
+            let stmts = self.statements(uri);
+            if let Some(recipe) = recipe_at(&stmts, offset) {
+                if let StatementKind::Recipe(_, params, body, ..) = &recipe.kind {
+                    if params.iter().any(|param| param.name == word) {
+                        return Ok(location(&uri_to_path(uri)?, code, recipe.span));
+                    }
+
+                    if let Some(span) = find_var(body, word) {
+                        return Ok(location(&uri_to_path(uri)?, code, span));
+                    }
+                }
+            }
+
+            for (path, _, file_code, file_stmts) in &files {
+                if let Some(span) = find_var(file_stmts, word) {
+                    return Ok(location(path, file_code, span));
+                }
+            }
+        } else {
+            let word = match self.molds.get(uri) {
+                Some(mold) => recipe_name(mold, &self.statements(uri), offset, word),
+                None => word.to_string(),
+            };
+
+            for (path, prefix, file_code, file_stmts) in &files {
+                if let Some(span) = find_recipe(file_stmts, prefix, &word) {
+                    return Ok(location(path, file_code, span));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn notify<W, P>(output: &mut W, method: &str, params: P) -> Result<(), Error>
+where
+    W: Write,
+    P: serde::Serialize,
+{
+    let message = json!({"jsonrpc": "2.0", "method": method, "params": params});
+    write_message(output, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    /// Start a server with a moldfile open, returning its URI and the diagnostics sent for it
+    fn open(name: &str, code: &str) -> (Server, Url, Vec<Diagnostic>) {
+        let dir = scratch(name, &[("moldfile", code)]);
+        let uri = Url::from_file_path(dir.join("moldfile")).unwrap();
+        let mut server = Server {
+            envs: vec![],
+            documents: IndexMap::new(),
+            molds: IndexMap::new(),
+        };
+
+        let params = json!({"textDocument": {
+            "uri": uri, "languageId": "mold", "version": 1, "text": code,
+        }});
+        let mut output = vec![];
+        server
+            .handle("textDocument/didOpen", params, &mut output)
+            .unwrap();
+
+        let message = read_message(&mut output.as_slice()).unwrap().unwrap();
+        let params = message["params"].clone();
+        let params: lsp_types::PublishDiagnosticsParams = serde_json::from_value(params).unwrap();
+        (server, uri, params.diagnostics)
+    }
+
+    fn at(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    fn hover_text(server: &Server, uri: &Url, pos: Position) -> Option<String> {
+        match server.hover(uri, pos).unwrap()?.contents {
+            HoverContents::Markup(content) => Some(content.value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        let code = "a\né😀x\n";
+        let x = code.find('x').unwrap();
+        assert_eq!(position(code, x), at(1, 3));
+        assert_eq!(offset(code, at(1, 3)), x);
+        assert_eq!(offset(code, at(1, 99)), code.len() - 1);
+        assert_eq!(offset(code, at(9, 0)), code.len());
+    }
+
+    #[test]
+    fn diagnostics_point_at_errors() {
+        let (_, _, found) = open("lsp-valid", "version \"0.7\"\nrecipe r { $ \"echo\" }\n");
+        assert!(found.is_empty());
+
+        let (_, _, found) = open("lsp-invalid", "version \"0.7\"\nrecipe r(x, x) {}\n");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].range.start, at(1, 0));
+        assert!(found[0].message.contains("Duplicate parameter"));
+    }
+
+    #[test]
+    fn hover_shows_expanded_commands() {
+        let code = "version \"0.7\"\nvar A = \"x y\"\n\
+                    recipe r {\n  help \"Does r\"\n  -$ \"echo $A\"\n  script \"python\" \"print(1)\"\n}\n\
+                    recipe s(p) { $ \"echo $p\" }\nrecipe t { require r }\n";
+        let (server, uri, _) = open("lsp-hover", code);
+
+        let text = hover_text(&server, &uri, at(8, 19)).unwrap();
+        assert!(text.starts_with("```\nr\n```\n\nDoes r\n\n```sh\n-$ echo 'x y'\n$ python3 "));
+
+        // recipes with required parameters can't be expanded
+        let text = hover_text(&server, &uri, at(7, 8)).unwrap();
+        assert_eq!(text, "```\ns(p)\n```");
+
+        let text = hover_text(&server, &uri, at(4, 12)).unwrap();
+        assert!(text.starts_with("```\n$A = x y\n```"), "{}", text);
+
+        // hovering must not write any scripts
+        let dir = uri_to_path(&uri).unwrap();
+        assert!(!dir.parent().unwrap().join(".mold").exists());
+    }
+
+    #[test]
+    fn definitions_resolve_names_inside_groups() {
+        let code = "version \"0.7\"\nvar A = \"a\"\nrecipe b { $ \"echo top\" }\n\
+                    group g {\n  recipe b(x) { $ \"echo $x $A\" }\n  recipe c { require b }\n}\n";
+        let (server, uri, _) = open("lsp-definition", code);
+
+        let found = server.definition(&uri, at(5, 21)).unwrap().unwrap();
+        assert_eq!(found.range, Range::new(at(4, 2), at(4, 32)));
+
+        let found = server.definition(&uri, at(4, 25)).unwrap().unwrap();
+        assert_eq!(found.range.start, at(4, 2));
+
+        let found = server.definition(&uri, at(4, 28)).unwrap().unwrap();
+        assert_eq!(found.range, Range::new(at(1, 0), at(1, 11)));
+    }
+
+    #[test]
+    fn completes_variables_and_recipes() {
+        let code = "version \"0.7\"\nvar A = \"a\"\nrecipe b(p) {\n  $ \"echo $A\"\n}\nrecipe c {\n  require b\n}\n";
+        let (server, uri, _) = open("lsp-completion", code);
+        let labels = |pos| -> Vec<String> {
+            let items = server.completion(&uri, pos).unwrap();
+            items.into_iter().map(|x| x.label).collect()
+        };
+
+        let vars = labels(at(3, 11));
+        for name in &["A", "p", "MOLD_ROOT", "MOLD_SOURCE"] {
+            assert!(vars.iter().any(|x| x == name), "{:?}", vars);
+        }
+        assert_eq!(labels(at(6, 10)), ["b", "c"]);
+    }
+}
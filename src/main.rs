@@ -40,6 +40,10 @@ pub struct Args {
     #[structopt(long = "clean")]
     pub clean: bool,
 
+    /// Rewrite the moldfile in the canonical style
+    #[structopt(long = "fmt")]
+    pub fmt: bool,
+
     /// Download all remote data
     #[structopt(long = "clone")]
     pub clone: bool,
@@ -56,11 +60,27 @@ pub struct Args {
     #[structopt(long = "no-vars")]
     pub no_vars: bool,
 
+    /// Treat references to undefined variables as an error
+    #[structopt(long = "strict")]
+    pub strict: bool,
+
+    /// Check the moldfile and its imports for problems without running anything
+    #[structopt(long = "check")]
+    pub check: bool,
+
+    /// List recipes rather than running the default ones
+    #[structopt(long = "list", short = "l")]
+    pub list: bool,
+
+    /// Include private recipes in the listing and allow running them directly
+    #[structopt(long = "all")]
+    pub all: bool,
+
     /// Explain commands to be run rather than executing them
     #[structopt(long = "explain", short = "x")]
     pub explain: bool,
 
-    /// Which recipe(s) to run
+    /// Which recipe(s) to run, or `lsp` to run a language server for moldfiles over stdin and stdout
     pub targets: Vec<String>,
 }
 
@@ -73,13 +93,23 @@ fn run(args: Args) -> Result<(), Error> {
     envs.push(std::env::consts::FAMILY.to_string());
     envs.push(std::env::consts::OS.to_string());
 
-    let filepath = Mold::discover(&Path::new("."), args.file.clone())?;
+    // early return if we ran `mold lsp`, since it doesn't need a moldfile to start
+    if args.targets == ["lsp"] {
+        return mold::lsp::run(envs);
+    }
+
+    let filepath = Mold::discover(Path::new("."), args.file.clone())?;
 
     // early return if we passed a --clean
     if args.clean {
         return Mold::clean_all(&filepath);
     }
 
+    // early return if we passed a --fmt
+    if args.fmt {
+        return Mold::format(&filepath);
+    }
+
     if let Some(import) = args.import {
         use std::io::prelude::*;
         let line = if let Some(prefix) = args.prefix {
@@ -102,42 +132,58 @@ fn run(args: Args) -> Result<(), Error> {
         return Ok(());
     }
 
-    let mold = Mold::init(&filepath, envs, args.git, !args.no_vars)?;
+    let mold = Mold::init(
+        &filepath,
+        envs,
+        args.git,
+        !args.no_vars,
+        args.strict,
+        args.explain || args.check,
+        !args.check,
+    )?;
 
     // early return if we passed a --update
     if args.update {
         return mold.update_all();
     }
 
+    // early return if we passed a --check
+    if args.check {
+        return mold.check();
+    }
+
     // list all variables if they're set
     if args.vars {
         mold.sh_vars()?;
         return Ok(());
     }
 
-    // early return and print help if we didn't pass any targets
-    if args.targets.is_empty() {
-        return mold.help();
+    // early return and print help if we passed --list, or didn't pass any targets and there's
+    // nothing to run by default
+    if args.list || (args.targets.is_empty() && mold.default_targets.is_empty()) {
+        return mold.help(args.all);
     }
 
+    let requested_targets = if args.targets.is_empty() {
+        mold.default_targets.clone()
+    } else {
+        mold.parse_targets(&args.targets, args.all)?
+    };
+
     // explain all of the given targets rather than executing them
     if args.explain {
-        for target_name in &args.targets {
-            mold.explain(target_name)?;
+        for target in &requested_targets {
+            mold.explain(target)?;
         }
 
         return Ok(());
     }
 
-    let requested_targets = args
-        .targets
-        .iter()
-        .map(std::string::ToString::to_string)
-        .collect();
     let all_targets = mold.find_all_dependencies(&requested_targets)?;
 
-    for target_name in &all_targets {
-        mold.execute(target_name)?;
+    mold.catch_interrupts()?;
+    for target in &all_targets {
+        mold.execute(target)?;
     }
 
     Ok(())
@@ -134,7 +134,7 @@ fn checkout_git(path: &Path, ref_: &str) -> Result<(), Error> {
     }
 
     let mut cmd = new_cmd();
-    cmd.args(&["fetch", "--all", "--prune"]).current_dir(path);
+    cmd.args(["fetch", "--all", "--prune"]).current_dir(path);
     cmd.spawn().and_then(|mut handle| handle.wait())?;
 
     let refs = vec![format!("tags/{}", ref_), format!("origin/{}", ref_)];
@@ -168,7 +168,7 @@ impl Remote {
         // first attempt to parse with an implicit https://
         let url = Url::parse(&format!("https://{}", &self.url)).or_else(|_| Url::parse(&self.url));
         let last_path = match url {
-            Ok(ref url) => url.path_segments().map(|mut x| x.next_back()).flatten(),
+            Ok(ref url) => url.path_segments().and_then(|mut x| x.next_back()),
             _ => None,
         };
 
@@ -262,12 +262,12 @@ impl Remote {
     }
 }
 
-impl ToString for Remote {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Remote {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if let Some(file) = &self.file {
-            format!("{}#{}/{}", self.url, self.ref_, file.display())
+            write!(f, "{}#{}/{}", self.url, self.ref_, file.display())
         } else {
-            format!("{}#{}", self.url, self.ref_)
+            write!(f, "{}#{}", self.url, self.ref_)
         }
     }
 }
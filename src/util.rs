@@ -1,6 +1,8 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
 
 pub fn hash_url_ref(url: &str, ref_: &str) -> String {
     hash_string(&format!("{}@{}", url, ref_))
@@ -11,3 +13,30 @@ pub fn hash_string(string: &str) -> String {
     string.hash(&mut hasher);
     format!("{:016x}", hasher.finish())
 }
+
+/// Locate an executable by searching the $PATH
+///
+/// Names containing a path separator are checked directly instead. On Windows,
+/// each extension in $PATHEXT is tried as well.
+pub fn find_command(name: &str) -> Option<PathBuf> {
+    let extensions: Vec<String> = match std::env::var("PATHEXT") {
+        Ok(exts) if std::env::consts::FAMILY == "windows" => std::iter::once(String::new())
+            .chain(exts.split(';').map(|x| x.to_lowercase()))
+            .collect(),
+        _ => vec![String::new()],
+    };
+
+    let check = |path: &Path| {
+        extensions
+            .iter()
+            .map(|ext| PathBuf::from(format!("{}{}", path.display(), ext)))
+            .find(|path| path.is_file())
+    };
+
+    if name.contains('/') || name.contains(std::path::MAIN_SEPARATOR) {
+        return check(Path::new(name));
+    }
+
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths).find_map(|dir| check(&dir.join(name)))
+}